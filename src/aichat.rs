@@ -1,4 +1,10 @@
-use std::process::Command;
+use crate::backend::{Backend, Completion};
+use crate::conversation::Conversation;
+use async_trait::async_trait;
+use std::process::{Output, Stdio};
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::sync::mpsc::UnboundedSender;
 use tracing::info;
 
 pub struct AiChat {
@@ -20,91 +26,162 @@ impl AiChat {
         }
     }
 
-    /// List the models available to the aichat binary
-    pub fn list_models(&self) -> Vec<String> {
-        let mut command = Command::new(self.binary_location.clone());
-        command.arg("--list-models");
-
-        // Add the config dir if it exists
+    /// Create a command for the aichat binary, with the config dir set if it exists
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.binary_location);
         if let Some(config_dir) = &self.config_dir {
             command.env("AICHAT_CONFIG_DIR", config_dir);
         }
+        // Don't leave aichat running if the request is abandoned
+        command.kill_on_drop(true);
+        command
+    }
 
-        let output = command.output().expect("Failed to execute command");
+    /// Create the command to run a conversation
+    fn prompt_command(&self, model: &Option<String>, conversation: &Conversation) -> Command {
+        let mut command = self.command();
+        if let Some(model) = model {
+            command.arg("--model").arg(model);
+        }
+        // For each media file, add the media flag and the path to the file
+        // Note that the conversation keeps the media files alive until the command is finished
+        let media = conversation.media();
+        if !media.is_empty() {
+            command.arg("--file");
+            for media_file in media {
+                command.arg(media_file.path());
+            }
+        }
+        command.arg("--").arg(render(conversation));
+        command
+    }
 
-        // split each line of the output into it's own string and return
-        output
-            .stdout
-            .split(|c| *c == b'\n')
-            .map(|s| String::from_utf8(s.to_vec()).unwrap())
-            .filter(|s| !s.is_empty())
-            .collect()
+    /// Run the command and wait for it to finish
+    async fn output(&self, command: &mut Command) -> Result<Output, String> {
+        command
+            .output()
+            .await
+            .map_err(|e| format!("Failed to run {}: {}", self.binary_location, e))
     }
+}
 
-    /// Get the default model for the current aichat config
-    pub fn default_model(&self) -> String {
-        let mut command = Command::new(self.binary_location.clone());
-        command.arg("--info");
+/// Render the conversation as the single prompt that aichat takes
+/// The system prompt and examples come first, followed by the transcript
+fn render(conversation: &Conversation) -> String {
+    let mut prompt = conversation.full_transcript();
+    // Append "ASSISTANT: " to indicate the assistant is speaking
+    prompt.push_str("ASSISTANT: ");
+    prompt
+}
 
-        // Add the config dir if it exists
-        if let Some(config_dir) = &self.config_dir {
-            command.env("AICHAT_CONFIG_DIR", config_dir);
+/// Turn the output of a finished prompt into the response
+fn into_response(output: Output) -> Result<String, String> {
+    // return the output as a string
+    if output.stdout.is_empty() {
+        // if stdout is empty, something is clearly wrong and we actually have an error
+        let stderr =
+            String::from_utf8(output.stderr).map_err(|_| "Error decoding stderr".to_string());
+        if let Ok(err) = stderr {
+            Result::Err(err)
+        } else {
+            stderr
         }
+    } else {
+        String::from_utf8(output.stdout).map_err(|_| "Error decoding stdout".to_string())
+    }
+}
+
+#[async_trait]
+impl Backend for AiChat {
+    /// List the models available to the aichat binary
+    async fn list_models(&self) -> Result<Vec<String>, String> {
+        let output = self.output(self.command().arg("--list-models")).await?;
+
+        // split each line of the output into it's own string and return
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect())
+    }
 
-        let output = command.output().expect("Failed to execute command");
+    /// Get the default model for the current aichat config
+    async fn default_model(&self) -> Result<String, String> {
+        let output = self.output(self.command().arg("--info")).await?;
 
         // The model is returned on it's own line beginning with "model"
         // so we can split the output by newlines and find the line that starts with "model"
         // Then we can split that line by whitespace and take the second element
-        output
-            .stdout
-            .split(|c| *c == b'\n')
-            .map(|s| String::from_utf8(s.to_vec()).unwrap())
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
             .find(|s| s.starts_with("model"))
-            .map(|s| s.split_whitespace().nth(1).unwrap().to_string())
-            .unwrap_or("default".to_string())
+            .and_then(|s| s.split_whitespace().nth(1))
+            .unwrap_or("default")
+            .to_string())
     }
 
-    pub fn execute(
+    async fn execute(
         &self,
         model: &Option<String>,
-        prompt: String,
-        media: Vec<matrix_sdk::media::MediaFileHandle>,
-    ) -> Result<String, String> {
-        let mut command = Command::new(&self.binary_location);
-        if let Some(model) = model {
-            command.arg("--model").arg(model);
-        }
-        if let Some(config_dir) = &self.config_dir {
-            command.env("AICHAT_CONFIG_DIR", config_dir);
-        }
-        // For each media file, add the media flag and the path to the file
-        // Note that we must not consume the media files, the handles need to persist until the command is finished
-        if !media.is_empty() {
-            command.arg("--file");
-            for media_file in &media {
-                command.arg(media_file.path());
-            }
-        }
-        command.arg("--").arg(prompt);
+        conversation: &Conversation,
+    ) -> Result<Completion, String> {
+        let mut command = self.prompt_command(model, conversation);
         info!("Running command: {:?}", command);
 
-        let output = command.output().expect("Failed to execute command");
+        let output = self.output(&mut command).await?;
 
         info!("Output: {:?}", output);
 
-        // return the output as a string
-        if output.stdout.is_empty() {
-            // if stdout is empty, something is clearly wrong and we actually have an error
-            let stderr =
-                String::from_utf8(output.stderr).map_err(|_| "Error decoding stderr".to_string());
-            if let Ok(err) = stderr {
-                Result::Err(err)
-            } else {
-                stderr
+        // aichat doesn't report the tokens used, so they're estimated
+        into_response(output).map(|text| Completion::estimated(conversation, text))
+    }
+
+    async fn execute_stream(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+        tx: UnboundedSender<String>,
+    ) -> Result<Completion, String> {
+        let mut command = self.prompt_command(model, conversation);
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        info!("Running command: {:?}", command);
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("Failed to run {}: {}", self.binary_location, e))?;
+        let mut stdout = child.stdout.take().ok_or("Failed to read stdout")?;
+
+        // Forward stdout as it is written, stderr is collected when the process exits
+        let read_stdout = async move {
+            let mut received = Vec::new();
+            let mut pending = Vec::new();
+            let mut buffer = [0; 1024];
+            while let Ok(n) = stdout.read(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..n]);
+                pending.extend_from_slice(&buffer[..n]);
+                // Only send complete characters, the rest waits for the next read
+                let valid = match std::str::from_utf8(&pending) {
+                    Ok(text) => text.len(),
+                    Err(e) => e.valid_up_to(),
+                };
+                if valid > 0 {
+                    let text = String::from_utf8_lossy(&pending[..valid]).to_string();
+                    let _ = tx.send(text);
+                    pending.drain(..valid);
+                }
             }
-        } else {
-            String::from_utf8(output.stdout).map_err(|_| "Error decoding stdout".to_string())
-        }
+            received
+        };
+        let (stdout, output) = tokio::join!(read_stdout, child.wait_with_output());
+        let mut output =
+            output.map_err(|e| format!("Failed to run {}: {}", self.binary_location, e))?;
+        output.stdout = stdout;
+
+        info!("Output: {:?}", output);
+
+        into_response(output).map(|text| Completion::estimated(conversation, text))
     }
 }
@@ -0,0 +1,231 @@
+// Backends
+// A backend is anything that can take a prompt and return a response from a model.
+// Chaz only talks to the backend through the `Backend` trait, so new providers can be
+// added without touching the Matrix side of things.
+
+use crate::aichat::AiChat;
+use crate::conversation::{estimate_tokens, Conversation};
+use crate::ollama::Ollama;
+use crate::openai::OpenAI;
+use crate::role::MessageRole;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{mpsc::UnboundedSender, Semaphore};
+
+/// A response from the model
+#[derive(Debug, Clone)]
+pub struct Completion {
+    /// The next message from the assistant
+    pub text: String,
+    /// Tokens used by the request
+    pub usage: Usage,
+}
+
+impl Completion {
+    /// A completion from a backend that doesn't report usage, so the tokens are estimated
+    pub fn estimated(conversation: &Conversation, text: String) -> Self {
+        let usage = Usage {
+            prompt_tokens: estimate_tokens(&conversation.full_transcript()) as u64,
+            completion_tokens: estimate_tokens(&text) as u64,
+        };
+        Completion { text, usage }
+    }
+}
+
+/// Number of tokens used by a request
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// A provider of models that chaz can send conversations to
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Run the conversation against the model, using the default model if none is given
+    /// Returns the next message from the assistant
+    async fn execute(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+    ) -> Result<Completion, String>;
+
+    /// Run the conversation against the model, sending each piece of the response to `tx` as it is generated
+    /// Returns the full response once generation is finished
+    /// Backends that can't stream send the whole response as a single piece
+    async fn execute_stream(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+        tx: UnboundedSender<String>,
+    ) -> Result<Completion, String> {
+        let response = self.execute(model, conversation).await?;
+        // The receiver may have gone away, but we still have the full response
+        let _ = tx.send(response.text.clone());
+        Ok(response)
+    }
+
+    /// List the models available from this backend
+    async fn list_models(&self) -> Result<Vec<String>, String>;
+
+    /// Get the model that is used when none is selected
+    async fn default_model(&self) -> Result<String, String>;
+}
+
+/// Find the name a model is listed under
+/// Model names from aichat are `client:model`, e.g. `openai:gpt-4o`, and match the bare
+/// name when the list doesn't have the full one
+pub fn find_model(model: &str, models: &[String]) -> Option<String> {
+    if models.iter().any(|name| name == model) {
+        return Some(model.to_string());
+    }
+    let (_, bare) = model.split_once(':')?;
+    models.iter().find(|name| *name == bare).cloned()
+}
+
+/// Use the configured default model, or the first model the backend lists
+pub async fn default_or_first(
+    backend: &dyn Backend,
+    default_model: &Option<String>,
+) -> Result<String, String> {
+    if let Some(model) = default_model {
+        return Ok(model.clone());
+    }
+    Ok(backend
+        .list_models()
+        .await?
+        .into_iter()
+        .next()
+        .unwrap_or("default".to_string()))
+}
+
+/// The role name chat APIs use for a message
+pub fn api_role(role: MessageRole) -> &'static str {
+    match role {
+        MessageRole::User => "user",
+        MessageRole::Assistant => "assistant",
+        MessageRole::Server => "system",
+    }
+}
+
+/// Read a streamed HTTP response line by line, calling `on_line` for each complete line
+pub async fn read_lines(
+    response: &mut reqwest::Response,
+    mut on_line: impl FnMut(&[u8]),
+) -> Result<(), String> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(newline) = buffer.iter().position(|c| *c == b'\n') {
+            let line = buffer.drain(..=newline).collect::<Vec<u8>>();
+            on_line(&line);
+        }
+    }
+    Ok(())
+}
+
+/// Wraps a backend to limit how many requests can run at the same time
+/// Requests wait for a permit before they are sent to the backend
+pub struct ConcurrencyLimit {
+    backend: Box<dyn Backend>,
+    permits: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(backend: Box<dyn Backend>, permits: Arc<Semaphore>) -> Self {
+        ConcurrencyLimit { backend, permits }
+    }
+}
+
+#[async_trait]
+impl Backend for ConcurrencyLimit {
+    async fn execute(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+    ) -> Result<Completion, String> {
+        let _permit = self.permits.acquire().await.map_err(|e| e.to_string())?;
+        self.backend.execute(model, conversation).await
+    }
+
+    async fn execute_stream(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+        tx: UnboundedSender<String>,
+    ) -> Result<Completion, String> {
+        let _permit = self.permits.acquire().await.map_err(|e| e.to_string())?;
+        self.backend.execute_stream(model, conversation, tx).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, String> {
+        self.backend.list_models().await
+    }
+
+    async fn default_model(&self) -> Result<String, String> {
+        self.backend.default_model().await
+    }
+}
+
+/// Backend selection in the config file
+/// The `type` field picks the backend, the remaining fields configure it
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackendConfig {
+    /// Run the aichat binary for each request
+    AiChat {
+        /// Location of the aichat binary
+        /// Defaults to `aichat` from the PATH
+        binary: Option<String>,
+        /// Config directory for aichat
+        config_dir: Option<String>,
+    },
+    /// Call an OpenAI compatible `/v1/chat/completions` API
+    OpenAI {
+        /// Base URL of the API, e.g. `http://localhost:8000/v1`
+        base_url: String,
+        /// API key, sent as a bearer token
+        api_key: Option<String>,
+        /// Models to offer
+        /// Defaults to the list returned by the server
+        models: Option<Vec<String>>,
+        /// Model to use when none is selected
+        /// Defaults to the first model in the list
+        default_model: Option<String>,
+    },
+    /// Call an Ollama server
+    Ollama {
+        /// Base URL of the server
+        /// Defaults to `http://localhost:11434`
+        base_url: Option<String>,
+        /// Model to use when none is selected
+        /// Defaults to the first pulled model
+        default_model: Option<String>,
+    },
+}
+
+/// Create the backend described by the config
+/// `aichat_config_dir` is the top level config option, kept for older configs
+pub fn new_backend(
+    config: Option<BackendConfig>,
+    aichat_config_dir: Option<String>,
+) -> Box<dyn Backend> {
+    match config {
+        Some(BackendConfig::AiChat { binary, config_dir }) => Box::new(AiChat::new(
+            binary.unwrap_or("aichat".to_string()),
+            config_dir.or(aichat_config_dir),
+        )),
+        Some(BackendConfig::OpenAI {
+            base_url,
+            api_key,
+            models,
+            default_model,
+        }) => Box::new(OpenAI::new(base_url, api_key, models, default_model)),
+        Some(BackendConfig::Ollama {
+            base_url,
+            default_model,
+        }) => Box::new(Ollama::new(base_url, default_model)),
+        None => Box::new(AiChat::new("aichat".to_string(), aichat_config_dir)),
+    }
+}
@@ -0,0 +1,183 @@
+// Conversations
+// The messages in a room are read into a `Conversation`, with the system prompt kept separate.
+// Each backend renders the conversation into whatever format its API expects.
+
+use crate::role::MessageRole;
+use matrix_sdk::{media::MediaFileHandle, ruma::OwnedEventId};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// How messages from users are attributed to the person who sent them
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeakerLabels {
+    /// Every user is just "USER"
+    #[default]
+    None,
+    /// Label messages with the sender's display name in the room
+    DisplayName,
+    /// Label messages with the sender's Matrix ID
+    UserId,
+}
+
+/// A single message in a conversation
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub role: MessageRole,
+    /// The Matrix user that sent the message
+    pub sender: Option<String>,
+    /// Name to attribute the message to, so the model can tell the speakers apart
+    pub label: Option<String>,
+    pub text: String,
+    /// The message describes something the sender did, e.g. "sent an image: cat.png",
+    /// instead of something they said
+    pub action: bool,
+    /// Files sent with the message
+    pub attachments: Vec<Arc<MediaFileHandle>>,
+    /// The Matrix event the message came from
+    pub event_id: Option<OwnedEventId>,
+    /// The Matrix event the message is a reply to
+    pub reply_to: Option<OwnedEventId>,
+}
+
+impl ChatMessage {
+    pub fn new(role: MessageRole, text: String) -> Self {
+        ChatMessage {
+            role,
+            sender: None,
+            label: None,
+            text,
+            action: false,
+            attachments: Vec::new(),
+            event_id: None,
+            reply_to: None,
+        }
+    }
+
+    /// Estimate the number of tokens the message will use
+    pub fn estimate_tokens(&self) -> usize {
+        // Every message has some overhead for the role and formatting
+        estimate_tokens(&self.labelled_text()) + 4
+    }
+
+    /// The text of the message, attributed to the speaker if it has a label
+    /// e.g. "Alice: hello" or "Alice sent an image: cat.png"
+    pub fn labelled_text(&self) -> String {
+        match &self.label {
+            Some(label) if self.action => format!("{} {}", label, self.text),
+            Some(label) => format!("{}: {}", label, self.text),
+            None => self.text.clone(),
+        }
+    }
+
+    /// Render the message as a single line of a transcript, e.g. "USER: hello" or "USER (Alice): hello"
+    pub fn transcript_line(&self) -> String {
+        let speaker = match &self.label {
+            Some(label) => format!("{} ({})", self.role, label),
+            None => self.role.to_string(),
+        };
+        if self.action {
+            format!("{} {}\n", speaker, self.text)
+        } else {
+            format!("{}: {}\n", speaker, self.text)
+        }
+    }
+}
+
+/// A conversation to send to a backend
+#[derive(Debug, Clone, Default)]
+pub struct Conversation {
+    /// The system prompt, from the role
+    pub system: Option<String>,
+    /// Example messages from the role, these come before the conversation
+    pub examples: Vec<ChatMessage>,
+    /// A recap of the earlier part of the conversation that no longer fits in the context
+    pub summary: Option<String>,
+    /// The messages in the conversation, oldest first
+    pub messages: Vec<ChatMessage>,
+}
+
+impl Conversation {
+    /// A conversation consisting of a single message from the user
+    pub fn from_prompt(prompt: String) -> Self {
+        Conversation {
+            messages: vec![ChatMessage::new(MessageRole::User, prompt)],
+            ..Default::default()
+        }
+    }
+
+    /// Add a message to the end of the conversation
+    pub fn push(&mut self, message: ChatMessage) {
+        self.messages.push(message);
+    }
+
+    /// All the files attached to the conversation, oldest first
+    pub fn media(&self) -> Vec<&MediaFileHandle> {
+        self.messages
+            .iter()
+            .flat_map(|message| message.attachments.iter().map(|media| media.as_ref()))
+            .collect()
+    }
+
+    /// Drop the oldest messages until the conversation fits in the token budget
+    /// The system prompt, examples and summary are always kept, as is the newest message
+    /// Returns the messages that were dropped, oldest first
+    pub fn truncate(&mut self, budget: usize) -> Vec<ChatMessage> {
+        let mut used = self.system.as_deref().map(estimate_tokens).unwrap_or(0)
+            + self.summary.as_deref().map(estimate_tokens).unwrap_or(0)
+            + self
+                .examples
+                .iter()
+                .map(|message| message.estimate_tokens())
+                .sum::<usize>();
+        // Walk back from the newest message until we run out of room
+        let mut keep = 0;
+        for message in self.messages.iter().rev() {
+            used += message.estimate_tokens();
+            if used > budget && keep > 0 {
+                break;
+            }
+            keep += 1;
+        }
+        let dropped = self.messages.len() - keep;
+        self.messages.drain(..dropped).collect()
+    }
+
+    /// The messages rendered as a transcript, excluding the system prompt and examples
+    pub fn transcript(&self) -> String {
+        self.messages
+            .iter()
+            .map(|message| message.transcript_line())
+            .collect()
+    }
+
+    /// The recap of the earlier conversation, introduced so the model knows what it is
+    pub fn summary_prompt(&self) -> Option<String> {
+        self.summary
+            .as_ref()
+            .map(|summary| format!("Summary of the earlier conversation: {}", summary))
+    }
+
+    /// The full conversation rendered as a transcript, with the system prompt, examples and summary
+    pub fn full_transcript(&self) -> String {
+        let mut transcript = self.system.clone().unwrap_or_default();
+        if !transcript.is_empty() {
+            transcript.push('\n');
+        }
+        for example in &self.examples {
+            transcript.push_str(&example.transcript_line());
+        }
+        if let Some(summary) = self.summary_prompt() {
+            transcript.push_str(&summary);
+            transcript.push('\n');
+        }
+        transcript.push_str(&self.transcript());
+        transcript
+    }
+}
+
+/// Roughly estimate the number of tokens in some text
+/// Most tokenizers average around 4 characters per token for English text
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
@@ -17,22 +17,95 @@ username: ""
 # Technically optional, but the bot won't respond without it
 #allow_list: ""
 
+# Optional. Regex for the accounts that can see everyone's usage with `.usage all`.
+# It has to match the whole user ID, e.g. "@alice:example.org|@bob:example.org". Defaults to nobody.
+#admin_list: ""
+
 # Optional. Not setting it here because reading it from an XDG library is safer.
 #state_dir: "$XDG_STATE_HOME/username"
 
+# Optional. Select the backend that the conversations are sent to.
+# Defaults to running the aichat binary.
+#backend:
+#  type: aichat
+#  binary: "aichat"
+#  config_dir: "$AICHAT_CONFIG_DIR"
+# Or talk to an OpenAI compatible API directly
+#backend:
+#  type: openai
+#  base_url: "http://localhost:8000/v1"
+#  api_key: ""
+#  models: []
+#  default_model: ""
+# Or talk to an Ollama server
+#backend:
+#  type: ollama
+#  base_url: "http://localhost:11434"
+#  default_model: ""
+
+# Optional. Limit how many requests can run on the backend at the same time. 0 = Unlimited.
+#backend_concurrency: 0
+
 # Optional, for setting a separate Aichat config directory
 # Aichat uses $AICHAT_CONFIG_DIR
 #aichat_config_dir: "$AICHAT_CONFIG_DIR"
 
+# Optional. Stream responses by posting a reply right away and editing it as the model generates.
+# The reply is edited every `tokens` tokens or every `interval_ms` milliseconds, whichever comes first.
+#streaming:
+#  tokens: 20
+#  interval_ms: 1000
+
 # Optional. This is a separate model to use for summarization
 #chat_summary_model: ""
 
+# Optional. Limit how many tokens of the conversation are sent to the model.
+# The oldest messages are dropped to fit, the role prompt and examples are always kept.
+# Tokens are estimated at ~4 characters each. Defaults to sending the whole conversation.
+#context_tokens: 4096
+
+# Optional. Summarize the messages that don't fit in context_tokens, instead of dropping them.
+# The summary is made with the chat_summary_model. Defaults to true.
+#summarize_history: true
+
+# Optional. Label messages with who sent them, so the model can tell people apart in group rooms.
+# One of `none`, `display_name` or `user_id`. Defaults to `none`, where everyone is just USER.
+#speaker_labels: none
+
+# Optional. Regenerate the answer to a message when the message is edited.
+# Defaults to false, which posts a notice offering to regenerate it with a 🔁 reaction.
+#regenerate_on_edit: false
+
+# Optional. Settings for individual models, overriding the defaults above
+# Prices are per million tokens, and are used for usage_limits.
+#models:
+#  - name: "openai:gpt-4o"
+#    context_tokens: 128000
+#    prompt_price: 2.50
+#    completion_price: 10.00
+
 # Optional. Set a role, A.K.A. system prompt, to use by default
 #role: ""
 
-# Optional. Set a per-account message limit. 0 = Unlimited.
+# Optional. Set a per-account limit on the total number of messages. 0 = Unlimited.
 #message_limit: 0
 
+# Optional. Limit the number of messages in a sliding window, per user or per room.
+# The window is one of minute, hour, day, week or month. The counts are saved in the state_dir.
+#rate_limits:
+#  - messages: 20
+#    window: hour
+#    per: user
+
+# Optional. Budgets on the tokens or cost of requests, per user or per room.
+# Tokens are reported by the backend, or estimated if it doesn't. The cost uses the prices in models.
+# The window is optional, without one the budget is on the total. Usage is saved in the state_dir.
+#usage_limits:
+#  - tokens: 100000
+#    cost: 1.00
+#    window: day
+#    per: user
+
 # Optional. Set a room size limit to respond in. 0 = Unlimited
 #room_size_limit: 0
 
@@ -0,0 +1,336 @@
+// History
+// The timeline of each room is cached so that it doesn't need to be paginated for every message.
+// The cache for a room is filled from the server the first time it's read,
+// and then kept up to date with the events that come in from sync.
+// Events that come in while it's being read are kept, and added after the ones from the server.
+// If a sync skips events the cache is dropped, so the gap is read from the server instead.
+
+use headjack::is_command;
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    media::{MediaFileHandle, MediaFormat, MediaRequest},
+    room::MessagesOptions,
+    ruma::{
+        api::client::relations::get_relating_events_with_rel_type,
+        events::{
+            relation::RelationType,
+            room::{
+                message::{MessageType, RoomMessageEventContent},
+                MediaSource,
+            },
+            AnySyncTimelineEvent,
+        },
+        serde::Raw,
+        EventId, OwnedEventId, OwnedRoomId, RoomId,
+    },
+    Client, Room,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info};
+
+/// The cached history of a single room
+#[derive(Default)]
+struct RoomHistory {
+    /// Events back to the most recent `.clear`, oldest first
+    /// Until the history has been read, only the events that came in from sync since it started
+    events: Vec<Raw<AnySyncTimelineEvent>>,
+    /// Whether the history has been read from the server
+    loaded: bool,
+    /// Media that has been downloaded, keyed by the event it was sent in
+    media: HashMap<OwnedEventId, Arc<MediaFileHandle>>,
+    /// Events from before the most recent `.clear` that have been read on their own, like pins
+    older: HashMap<OwnedEventId, Raw<AnySyncTimelineEvent>>,
+    /// The order `older` was filled in, so the oldest lookups are forgotten first
+    older_order: VecDeque<OwnedEventId>,
+    /// The newest edit of events that were read on their own, if they have one
+    edits: HashMap<OwnedEventId, Option<Raw<AnySyncTimelineEvent>>>,
+}
+
+impl RoomHistory {
+    /// Add a new event to the end of the history, unless it's already there
+    fn add(&mut self, event: Raw<AnySyncTimelineEvent>) {
+        let event_id = event.get_field::<OwnedEventId>("event_id").unwrap_or(None);
+        if event_id.is_some()
+            && self.events.iter().rev().any(|cached| {
+                cached.get_field::<OwnedEventId>("event_id").unwrap_or(None) == event_id
+            })
+        {
+            return;
+        }
+        if is_clear(&event) {
+            // Everything before a `.clear` is ignored, so there's no need to keep it
+            // Pins and answers from before it are read again when they're needed
+            self.events.clear();
+            self.media.clear();
+        }
+        self.events.push(event);
+    }
+
+    /// Keep an event that was read on its own, forgetting the oldest if there are too many
+    fn remember(&mut self, event_id: OwnedEventId, event: Raw<AnySyncTimelineEvent>) {
+        if self.older.insert(event_id.clone(), event).is_none() {
+            self.older_order.push_back(event_id);
+        }
+        while self.older_order.len() > MAX_OLDER_EVENTS {
+            if let Some(oldest) = self.older_order.pop_front() {
+                self.older.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Most events read on their own that are cached per room
+const MAX_OLDER_EVENTS: usize = 256;
+
+lazy_static! {
+    /// Cached history of each room
+    static ref GLOBAL_HISTORY: Mutex<HashMap<OwnedRoomId, RoomHistory>> = Mutex::new(HashMap::new());
+}
+
+/// Keep the cached history up to date with new events
+/// This needs to be registered before any handlers that read the history,
+/// so that the history includes the event being handled
+pub fn register_history_handler(client: &Client) {
+    client.add_event_handler(|event: Raw<AnySyncTimelineEvent>, room: Room| async move {
+        push(room.room_id(), event);
+    });
+
+    // A limited sync skips events, so the cache would have a gap that never gets filled
+    let mut updates = client.subscribe_to_all_room_updates();
+    tokio::spawn(async move {
+        loop {
+            match updates.recv().await {
+                Ok(updates) => {
+                    for (room_id, update) in updates.join {
+                        if update.timeline.limited {
+                            info!("Limited sync in {}, clearing the cached history", room_id);
+                            invalidate(&room_id);
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {
+                    // We can't tell which rooms had gaps in the updates we missed
+                    info!("Missed room updates, clearing all cached history");
+                    GLOBAL_HISTORY.lock().unwrap().clear();
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Add a new event to the cached history of the room
+fn push(room_id: &RoomId, event: Raw<AnySyncTimelineEvent>) {
+    let mut history = GLOBAL_HISTORY.lock().unwrap();
+    // Nothing to do until the history is read for the first time
+    let Some(room_history) = history.get_mut(room_id) else {
+        return;
+    };
+    let event_type = event.get_field::<String>("type").unwrap_or(None);
+    if event_type.as_deref() == Some("m.room.redaction") {
+        // The redacted event could be anywhere in the history, so start over
+        info!("Redaction in {}, clearing the cached history", room_id);
+        history.remove(room_id);
+        return;
+    }
+    room_history.add(event);
+}
+
+/// Forget the cached history of the room, it will be read from the server next time
+fn invalidate(room_id: &RoomId) {
+    GLOBAL_HISTORY.lock().unwrap().remove(room_id);
+}
+
+/// Get the events in the room back to the most recent `.clear`, newest first
+pub async fn events(room: &Room) -> Vec<Raw<AnySyncTimelineEvent>> {
+    {
+        // Start keeping new events before reading, so none are missed while we wait
+        let mut history = GLOBAL_HISTORY.lock().unwrap();
+        let room_history = history.entry(room.room_id().to_owned()).or_default();
+        if room_history.loaded {
+            return room_history.events.iter().rev().cloned().collect();
+        }
+    }
+
+    let events = fetch(room).await;
+    let mut history = GLOBAL_HISTORY.lock().unwrap();
+    match history.get_mut(room.room_id()) {
+        Some(room_history) if !room_history.loaded => {
+            let arrived = std::mem::take(&mut room_history.events);
+            room_history.events = events.into_iter().rev().collect();
+            for event in arrived {
+                room_history.add(event);
+            }
+            room_history.loaded = true;
+            room_history.events.iter().rev().cloned().collect()
+        }
+        // Another handler read the history first
+        Some(room_history) => room_history.events.iter().rev().cloned().collect(),
+        // The history was dropped while we were reading it, so it may be out of date
+        None => events,
+    }
+}
+
+/// Get a single event from the room, reading it from the server if it isn't cached
+pub async fn event(room: &Room, event_id: &EventId) -> Option<Raw<AnySyncTimelineEvent>> {
+    if let Some(room_history) = GLOBAL_HISTORY.lock().unwrap().get(room.room_id()) {
+        let cached = room_history
+            .events
+            .iter()
+            .rev()
+            .find(|event| {
+                event
+                    .get_field::<OwnedEventId>("event_id")
+                    .unwrap_or(None)
+                    .as_deref()
+                    == Some(event_id)
+            })
+            .or_else(|| room_history.older.get(event_id));
+        if let Some(event) = cached {
+            return Some(event.clone());
+        }
+    }
+
+    let event = match room.event(event_id).await {
+        Ok(event) => event.event.cast::<AnySyncTimelineEvent>(),
+        Err(e) => {
+            error!("Error reading event {}: {}", event_id, e);
+            return None;
+        }
+    };
+    if let Some(room_history) = GLOBAL_HISTORY.lock().unwrap().get_mut(room.room_id()) {
+        room_history.remember(event_id.to_owned(), event.clone());
+    }
+    Some(event)
+}
+
+/// Get the newest edit of an event by its sender, reading it from the server if it isn't cached
+/// Edits of events in the history are already there, this is for events from before the `.clear`
+pub async fn latest_edit(
+    room: &Room,
+    event: &Raw<AnySyncTimelineEvent>,
+) -> Option<Raw<AnySyncTimelineEvent>> {
+    let event_id = event
+        .get_field::<OwnedEventId>("event_id")
+        .unwrap_or(None)?;
+    let sender = event.get_field::<String>("sender").unwrap_or(None)?;
+    if let Some(cached) = GLOBAL_HISTORY
+        .lock()
+        .unwrap()
+        .get(room.room_id())
+        .and_then(|room_history| room_history.edits.get(&event_id))
+    {
+        return cached.clone();
+    }
+
+    let request = get_relating_events_with_rel_type::v1::Request::new(
+        room.room_id().to_owned(),
+        event_id.clone(),
+        RelationType::Replacement,
+    );
+    // The edits are returned newest first
+    let edit = match room.client().send(request, None).await {
+        Ok(response) => response
+            .chunk
+            .into_iter()
+            .map(|edit| edit.cast::<AnySyncTimelineEvent>())
+            .find(|edit| {
+                edit.get_field::<String>("sender").unwrap_or(None) == Some(sender.clone())
+            }),
+        Err(e) => {
+            error!("Error reading the edits of {}: {}", event_id, e);
+            return None;
+        }
+    };
+    if let Some(room_history) = GLOBAL_HISTORY.lock().unwrap().get_mut(room.room_id()) {
+        room_history.edits.insert(event_id, edit.clone());
+    }
+    edit
+}
+
+/// Read the events in the room from the server, back to the most recent `.clear`
+/// Returns the events newest first
+async fn fetch(room: &Room) -> Vec<Raw<AnySyncTimelineEvent>> {
+    let mut events = Vec::new();
+    let mut options = MessagesOptions::backward();
+    'outer: while let Ok(batch) = room.messages(options).await {
+        // This assumes that the messages are in reverse order
+        for message in batch.chunk {
+            let event = message.event.cast::<AnySyncTimelineEvent>();
+            let clear = is_clear(&event);
+            events.push(event);
+            if clear {
+                // if the message was a clear command, we are finished
+                break 'outer;
+            }
+        }
+        if let Some(token) = batch.end {
+            options = MessagesOptions::backward().from(Some(token.as_str()));
+        } else {
+            break;
+        }
+    }
+    info!("Read {} events from {}", events.len(), room.room_id());
+    events
+}
+
+/// Check if the event is a `.clear` command
+fn is_clear(event: &Raw<AnySyncTimelineEvent>) -> bool {
+    event
+        .get_field::<RoomMessageEventContent>("content")
+        .unwrap_or(None)
+        .is_some_and(|content| match &content.msgtype {
+            MessageType::Text(text_content) => {
+                is_command(&text_content.body) && text_content.body.starts_with(".clear")
+            }
+            _ => false,
+        })
+}
+
+/// Get a media file sent in the room, downloading it if it isn't cached
+pub async fn media(
+    room: &Room,
+    event_id: Option<&EventId>,
+    source: &MediaSource,
+    mimetype: Option<&str>,
+) -> Option<Arc<MediaFileHandle>> {
+    if let Some(event_id) = event_id {
+        let cached = GLOBAL_HISTORY
+            .lock()
+            .unwrap()
+            .get(room.room_id())
+            .and_then(|room_history| room_history.media.get(event_id).cloned());
+        if cached.is_some() {
+            return cached;
+        }
+    }
+
+    let request = MediaRequest {
+        source: source.clone(),
+        format: MediaFormat::File,
+    };
+    let mime = mimetype?.parse().ok()?;
+    let file = match room
+        .client()
+        .media()
+        .get_media_file(&request, None, &mime, true, None)
+        .await
+    {
+        Ok(file) => Arc::new(file),
+        Err(e) => {
+            error!("Error downloading media: {}", e);
+            return None;
+        }
+    };
+
+    if let Some(event_id) = event_id {
+        if let Some(room_history) = GLOBAL_HISTORY.lock().unwrap().get_mut(room.room_id()) {
+            room_history.media.insert(event_id.to_owned(), file.clone());
+        }
+    }
+    Some(file)
+}
@@ -1,8 +1,42 @@
 mod aichat;
-use aichat::AiChat;
+
+mod backend;
+use backend::{Backend, BackendConfig, Completion, ConcurrencyLimit};
+
+mod conversation;
+use conversation::{ChatMessage, Conversation, SpeakerLabels};
+
+mod history;
+
+mod model;
+use model::ModelDetails;
+
+mod ollama;
+
+mod openai;
+
+mod pins;
+
+mod stream;
+use stream::StreamingConfig;
+
+mod typing;
+
+mod ratelimit;
+use ratelimit::{RateLimit, RateLimiter};
+
+mod usage;
+use usage::{Metered, Tally, UsageLedger, UsageLimit};
 
 mod role;
-use role::RoleDetails;
+use role::{MessageRole, RoleDetails};
+
+mod settings;
+use settings::RoomSettings;
+
+mod split;
+
+mod state;
 
 mod defaults;
 use defaults::DEFAULT_CONFIG;
@@ -11,25 +45,33 @@ use clap::Parser;
 use headjack::*;
 use lazy_static::lazy_static;
 use matrix_sdk::{
-    media::{MediaFileHandle, MediaFormat, MediaRequest},
-    room::MessagesOptions,
     ruma::{
         api::client::receipt::create_receipt::v3::ReceiptType,
         events::{
+            reaction::OriginalSyncReactionEvent,
             receipt::ReceiptThread::Unthreaded,
+            relation::Annotation,
             room::message::{
-                AddMentions, ForwardThread, MessageType, OriginalSyncRoomMessageEvent,
-                RoomMessageEventContent,
+                AddMentions, ForwardThread, MessageType, OriginalSyncRoomMessageEvent, Relation,
+                ReplacementMetadata, RoomMessageEventContent,
             },
         },
-        OwnedUserId,
+        OwnedEventId, OwnedRoomId, OwnedUserId,
     },
     Room, RoomMemberships, RoomState,
 };
 use regex::Regex;
 use serde::Deserialize;
 use std::format;
-use std::{collections::HashMap, fs::File, io::Read, path::PathBuf, sync::Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Read,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
 use tracing::{error, info, warn};
 
 #[derive(Parser)]
@@ -48,31 +90,101 @@ pub struct Config {
     password: Option<String>,
     /// Allow list of which accounts we will respond to
     allow_list: Option<String>,
-    /// Per-account message limit while the bot is running
+    /// Regex for the accounts that can see everyone's usage with `.usage all`
+    /// It has to match the whole user ID
+    admin_list: Option<String>,
+    /// Per-account limit on the total number of messages
     message_limit: Option<u64>,
+    /// Limits on the number of messages in a window of time, per account or per room
+    rate_limits: Option<Vec<RateLimit>>,
+    /// Budgets on the tokens or cost of the requests, per account or per room
+    usage_limits: Option<Vec<UsageLimit>>,
     /// Room size limit to respond to
     room_size_limit: Option<u64>,
     /// Set the state directory for chaz
     /// Defaults to $XDG_STATE_HOME/chaz
     state_dir: Option<String>,
+    /// Backend to send the conversations to
+    /// Defaults to running aichat
+    backend: Option<BackendConfig>,
+    /// Maximum number of requests running on the backend at once
+    backend_concurrency: Option<usize>,
     /// Set the config directory for aichat
     /// Allows for multiple instances setups of aichat
     aichat_config_dir: Option<String>,
+    /// Stream responses into the room by editing the reply as it is generated
+    streaming: Option<StreamingConfig>,
     /// Model to use for summarizing chats
     /// Used for setting the room name/topic
     chat_summary_model: Option<String>,
+    /// Number of tokens of conversation to send to the model
+    /// Older messages are dropped to fit, defaults to sending everything
+    context_tokens: Option<usize>,
+    /// Summarize the messages that don't fit in the context instead of dropping them
+    /// Uses the chat summary model, defaults to true
+    summarize_history: Option<bool>,
+    /// Label messages with who sent them, for rooms with several people
+    /// Defaults to none
+    speaker_labels: Option<SpeakerLabels>,
+    /// Regenerate the answer to a message when the message is edited
+    /// Defaults to false, which offers to regenerate it instead
+    regenerate_on_edit: Option<bool>,
+    /// Settings for specific models
+    models: Option<Vec<ModelDetails>>,
     /// Default role
     role: Option<String>,
     /// Definitions of roles
     roles: Option<Vec<RoleDetails>>,
 }
 
+/// A conversation in a room, the main timeline or a thread
+type ConversationKey = (OwnedRoomId, Option<OwnedEventId>);
+
+/// Tokens set aside for the recap of the history that doesn't fit in the context
+const RECAP_TOKENS: usize = 256;
+
 lazy_static! {
     /// Holds the config for the bot
     static ref GLOBAL_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
 
-    /// Count of the global messages per user
-    static ref GLOBAL_MESSAGES: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+    /// Count of the messages per user and room, saved in the state directory
+    static ref GLOBAL_RATE_LIMITER: Mutex<RateLimiter> = {
+        let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+        Mutex::new(RateLimiter::load(
+            get_state_dir(&config).map(|dir| dir.join("rate_limits.yaml")),
+        ))
+    };
+
+    /// Tokens used and their cost per user, room and model, saved in the state directory
+    static ref GLOBAL_USAGE: Arc<Mutex<UsageLedger>> = {
+        let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+        Arc::new(Mutex::new(UsageLedger::load(
+            get_state_dir(&config).map(|dir| dir.join("usage.yaml")),
+        )))
+    };
+
+    /// Recap of the history that doesn't fit in the context, per room and thread
+    /// Stored with the id of the newest message in the recap
+    static ref GLOBAL_SUMMARIES: Mutex<HashMap<ConversationKey, (OwnedEventId, String)>> =
+        Mutex::new(HashMap::new());
+
+    /// Permits for running requests on the backend
+    static ref BACKEND_PERMITS: Arc<Semaphore> = {
+        let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+        match config.backend_concurrency {
+            Some(0) | None => Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            Some(limit) => Arc::new(Semaphore::new(limit)),
+        }
+    };
+
+    /// The backend, built once so its HTTP client and connections are shared by every request
+    static ref GLOBAL_BACKEND: Arc<dyn Backend> = {
+        let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+        Arc::new(ConcurrencyLimit::new(
+            backend::new_backend(config.backend, config.aichat_config_dir),
+            BACKEND_PERMITS.clone(),
+        ))
+    };
 }
 
 #[tokio::main]
@@ -105,6 +217,10 @@ async fn main() -> anyhow::Result<()> {
         error!("Error logging in: {e}");
     }
 
+    // Keep the cached history of each room up to date
+    // This is registered before the commands and the text handler so they see the newest message
+    history::register_history_handler(&bot.client());
+
     // React to invites.
     // We set this up before the initial sync so that we join rooms
     // even if they were invited before the bot was started.
@@ -125,9 +241,9 @@ async fn main() -> anyhow::Result<()> {
     // we don't expose it because one might want to avoid spoiling the role prompt
     // (full exposition can kind of ruin the magic of a quirky character)
     bot.register_text_command("fullcontext", None, |_, _, room| async move {
-        let (mut context, _, _, _) = get_context(&room).await.unwrap();
-        context = add_role(&context);
-        context.insert_str(0, ".fullcontext:\n");
+        let (mut conversation, settings) = get_context(&room, &None).await.unwrap();
+        add_role(&mut conversation, &settings);
+        let context = format!(".fullcontext:\n{}", conversation.full_transcript());
         let content = RoomMessageEventContent::notice_plain(context);
         room.send(content).await.unwrap();
         Ok(())
@@ -139,8 +255,8 @@ async fn main() -> anyhow::Result<()> {
         "print",
         "Print the conversation".to_string(),
         |_, _, room| async move {
-            let (mut context, _, _, _) = get_context(&room).await.unwrap();
-            context.insert_str(0, ".context:\n");
+            let (conversation, _) = get_context(&room, &None).await.unwrap();
+            let context = format!(".context:\n{}", conversation.transcript());
             let content = RoomMessageEventContent::notice_plain(context);
             room.send(content).await.unwrap();
             Ok(())
@@ -158,25 +274,39 @@ async fn main() -> anyhow::Result<()> {
             let input = text.trim_start_matches(".send").trim();
 
             // But we do need to read the context to figure out the model to use
-            let (_, model, _, _) = get_context(&room).await.unwrap();
+            let (_, settings) = get_context(&room, &None).await.unwrap();
+            let model = settings.model;
 
             info!(
                 "Request: {} - {}",
                 sender.as_str(),
                 input.replace('\n', " ")
             );
-            if let Ok(result) = get_backend().execute(&model, input.to_string(), Vec::new()) {
-                // Add the prefix ".response:\n" to the result
-                // That way we can identify our own responses and ignore them for context
-                info!(
-                    "Response: {} - {}",
-                    sender.as_str(),
-                    result.replace('\n', " ")
-                );
-                let result = format!(".response:\n{}", result);
-                let content = RoomMessageEventContent::notice_plain(result);
-
-                room.send(content).await.unwrap();
+            let conversation = Conversation::from_prompt(input.to_string());
+            let backend = get_metered_backend(&room, &sender);
+            match typing::while_typing(&room, backend.execute(&model, &conversation)).await {
+                Ok(Completion { text: result, .. }) => {
+                    // Add the prefix ".response:\n" to the result
+                    // That way we can identify our own responses and ignore them for context
+                    info!(
+                        "Response: {} - {}",
+                        sender.as_str(),
+                        result.replace('\n', " ")
+                    );
+                    let result = format!(".response:\n{}", result);
+                    let content = RoomMessageEventContent::notice_markdown(result);
+
+                    room.send(content).await.unwrap();
+                }
+                Err(stderr) => {
+                    error!("Error: {}", stderr.replace('\n', " "));
+                    room.send(RoomMessageEventContent::notice_plain(format!(
+                        ".error: {}",
+                        stderr.replace('\n', " ")
+                    )))
+                    .await
+                    .unwrap();
+                }
             }
             Ok(())
         },
@@ -193,6 +323,42 @@ async fn main() -> anyhow::Result<()> {
     bot.register_text_command("list", "List available models".to_string(), list_models)
         .await;
 
+    bot.register_text_command("role", "<role> - Select the role to use".to_string(), role)
+        .await;
+
+    bot.register_text_command("roles", "List available roles".to_string(), list_roles)
+        .await;
+
+    bot.register_text_command(
+        "system",
+        "<prompt> - Set a custom system prompt, or `reset` to go back to the role".to_string(),
+        system,
+    )
+    .await;
+
+    bot.register_text_command(
+        "regen",
+        "[model] - Regenerate the last answer, optionally with a different model".to_string(),
+        regen,
+    )
+    .await;
+
+    bot.register_text_command(
+        "compare",
+        "<model> <model>... <message> - Send this message to several models without context"
+            .to_string(),
+        compare,
+    )
+    .await;
+
+    bot.register_text_command(
+        "usage",
+        "[all] - Show your usage and remaining quota, or everyone's with `all` (admins only)"
+            .to_string(),
+        usage,
+    )
+    .await;
+
     bot.register_text_command(
         "clear",
         "Ignore all messages before this point".to_string(),
@@ -226,9 +392,11 @@ async fn main() -> anyhow::Result<()> {
         "lurk",
         "Do not respond (does not affect notices)".to_string(),
         |_, _, room| async move {
-            room.send(RoomMessageEventContent::notice_plain(
-                ".lurk: Will not engage in conversation",
-            ))
+            let note = save_setting(&room, |settings| settings.lurk = Some(true)).await;
+            room.send(RoomMessageEventContent::notice_plain(format!(
+                ".lurk: Will not engage in conversation{}",
+                note
+            )))
             .await
             .unwrap();
             Ok(())
@@ -240,9 +408,43 @@ async fn main() -> anyhow::Result<()> {
         "nolurk",
         "Stop lurking".to_string(),
         |_, _, room| async move {
-            room.send(RoomMessageEventContent::notice_plain(
-                ".lurk: Will respond normally",
-            ))
+            let note = save_setting(&room, |settings| settings.lurk = Some(false)).await;
+            room.send(RoomMessageEventContent::notice_plain(format!(
+                ".lurk: Will respond normally{}",
+                note
+            )))
+            .await
+            .unwrap();
+            Ok(())
+        },
+    )
+    .await;
+
+    bot.register_text_command(
+        "mention",
+        "Only respond to messages that mention me or reply to me".to_string(),
+        |_, _, room| async move {
+            let note = save_setting(&room, |settings| settings.mention = Some(true)).await;
+            room.send(RoomMessageEventContent::notice_plain(format!(
+                ".mention: Will only respond when mentioned or replied to{}",
+                note
+            )))
+            .await
+            .unwrap();
+            Ok(())
+        },
+    )
+    .await;
+
+    bot.register_text_command(
+        "nomention",
+        "Respond to every message".to_string(),
+        |_, _, room| async move {
+            let note = save_setting(&room, |settings| settings.mention = Some(false)).await;
+            room.send(RoomMessageEventContent::notice_plain(format!(
+                ".mention: Will respond to every message{}",
+                note
+            )))
             .await
             .unwrap();
             Ok(())
@@ -263,32 +465,87 @@ async fn main() -> anyhow::Result<()> {
         room.send_single_receipt(ReceiptType::Read, Unthreaded, event.event_id.to_owned())
             .await
             .unwrap();
-        if rate_limit(&room, &sender).await {
-            Ok("rate limited".to_string())
-        } else if sender == room.client().user_id().unwrap().as_str() {
+        if sender == room.client().user_id().unwrap().as_str() {
             Ok("not responding to myself".to_string())
-        } else if let Ok((context, model, lurk, media)) = get_context(&room).await {
-            if !lurk.unwrap_or(false) {
+        } else if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
+            // Edits aren't new messages, but they might change the answer to the message
+            if let MessageType::Text(text_content) = &replacement.new_content.msgtype {
+                if is_command(&text_content.body) {
+                    return Ok("ignoring edited command".to_string());
+                }
+            }
+            respond_to_edit(&room, &sender, &replacement.event_id).await
+        } else if let Ok((mut conversation, settings)) =
+            get_context(&room, &thread_root(&event.content)).await
+        {
+            // Messages the bot won't answer don't count towards the rate limits
+            if settings.mention.unwrap_or(false) && !mentions_bot(&room, &event).await {
+                Ok("not mentioned".to_string())
+            } else if settings.lurk.unwrap_or(false) {
+                Ok("lurking".to_string())
+            } else if rate_limit(&room, &sender).await {
+                Ok("rate limited".to_string())
+            } else {
                 // If it's not a command, we should send the full context without commands to the server
-                let mut context = add_role(&context);
-                // Append "ASSISTANT: " to the context string to indicate the assistant is speaking
-                context.push_str("ASSISTANT: ");
-
-                info!(
-                    "Request: {} - {}",
-                    sender.as_str(),
-                    context.replace('\n', " ")
-                );
-                match get_backend().execute(&model, context, media) {
+                add_role(&mut conversation, &settings);
+                let model = settings.model;
+                let event = event.into_full_event(room.room_id().to_owned());
+                // Show that we're typing for as long as the backend takes
+                let result = typing::while_typing(&room, async {
+                    let thread = thread_root(&event.content);
+                    fit_context_with_recap(&room, &sender, &thread, &mut conversation, &model)
+                        .await;
+
+                    info!(
+                        "Request: {} - {}",
+                        sender.as_str(),
+                        conversation.full_transcript().replace('\n', " ")
+                    );
+                    let backend = get_metered_backend(&room, &sender);
+                    if let Some(streaming) = get_streaming_config() {
+                        stream::respond(
+                            &room,
+                            &event,
+                            backend.as_ref(),
+                            &model,
+                            &conversation,
+                            &streaming,
+                        )
+                        .await
+                        .map(|completion| completion.text)
+                    } else {
+                        match backend.execute(&model, &conversation).await {
+                            Ok(Completion { text: stdout, .. }) => {
+                                // Long responses are split into a chain of replies
+                                let chunks = split::split_message(&stdout);
+                                let response = room
+                                    .send(
+                                        RoomMessageEventContent::text_markdown(&chunks[0])
+                                            .make_reply_to(
+                                                &event,
+                                                ForwardThread::Yes,
+                                                AddMentions::No,
+                                            ),
+                                    )
+                                    .await
+                                    .unwrap();
+                                split::send_chain(
+                                    &room,
+                                    response.event_id,
+                                    &thread_root(&event.content),
+                                    &chunks[1..],
+                                )
+                                .await?;
+                                Ok(stdout)
+                            }
+                            Err(stderr) => Err(stderr),
+                        }
+                    }
+                })
+                .await;
+                match result {
                     Ok(stdout) => {
                         info!("Response: {}", stdout.replace('\n', " "));
-                        room.send(RoomMessageEventContent::text_plain(stdout).make_reply_to(
-                            &event.into_full_event(room.room_id().to_owned()),
-                            ForwardThread::No,
-                            AddMentions::No,
-                        ))
-                        .await
-                        .unwrap();
                         Ok("responded".to_string())
                     }
                     Err(stderr) => {
@@ -302,14 +559,14 @@ async fn main() -> anyhow::Result<()> {
                         Err("error: {stderr}".to_string())
                     }
                 }
-            } else {
-                Ok("lurking".to_string())
             }
         } else {
             Err("could not get context".to_string())
         }
     });
 
+    register_reaction_handler(&bot, react);
+
     // Syncs to the current state
     if let Err(e) = bot.sync().await {
         error!("Error syncing: {e}");
@@ -354,64 +611,189 @@ where
     );
 }
 
-/// Prepend the role defined in the global config
-fn add_role(context: &str) -> String {
+// Like `register_text_handler`, but for reactions to messages
+// The allow list has to be checked here, since headjack doesn't handle reactions
+pub fn register_reaction_handler<F, Fut>(bot: &Bot, callback: F)
+where
+    F: FnOnce(OwnedUserId, String, OwnedEventId, Room) -> Fut + Send + 'static + Clone + Sync,
+    Fut: std::future::Future<Output = Result<String, String>> + Send + 'static,
+{
+    let client = bot.client();
+    client.add_event_handler(
+        move |event: OriginalSyncReactionEvent, room: Room| async move {
+            // Ignore reactions from rooms we're not in
+            if room.state() != RoomState::Joined {
+                return;
+            }
+            if !is_allowed(&room, &event.sender) {
+                return;
+            }
+            let Annotation { event_id, key, .. } = event.content.relates_to;
+            match callback(event.sender, key.clone(), event_id, room).await {
+                Err(e) => {
+                    error!("Error responding to reaction: {}\nError: {:?}", key, e);
+                }
+                Ok(res) => info!(res),
+            }
+        },
+    );
+}
+
+/// Check if the sender is in the allow list, and isn't the bot itself
+fn is_allowed(room: &Room, sender: &OwnedUserId) -> bool {
+    if room.client().user_id().map(|uid| uid.as_str()) == Some(sender.as_str()) {
+        return false;
+    }
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    match config.allow_list {
+        Some(allow_list) => Regex::new(&allow_list)
+            .map(|regex| regex.is_match(sender.as_str()))
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Check if the sender can see everyone's usage
+/// The pattern has to match the whole user ID,
+/// so `@alice:example.org` doesn't also match `@alice:example.org.evil.net`
+fn is_admin(sender: &OwnedUserId) -> bool {
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    match config.admin_list {
+        Some(admin_list) => Regex::new(&format!("^(?:{})$", admin_list))
+            .map(|regex| regex.is_match(sender.as_str()))
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Prepend the system prompt or role selected for the room
+/// Falls back to the role defined in the global config
+fn add_role(conversation: &mut Conversation, settings: &RoomSettings) {
+    if let Some(system) = &settings.system {
+        conversation.system = Some(system.clone());
+        conversation.examples = Vec::new();
+        return;
+    }
     let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
     role::prepend_role(
-        context.to_string(),
-        config.role.clone(),
+        conversation,
+        settings.role.clone().or(config.role.clone()),
         config.roles.clone(),
         DEFAULT_CONFIG.roles.clone(),
     )
 }
 
-/// Rate limit the user to a set number of messages
-/// Returns true if the user is being rate limited
-async fn rate_limit(room: &Room, sender: &OwnedUserId) -> bool {
-    let room_size = room
-        .members(RoomMemberships::ACTIVE)
-        .await
-        .unwrap_or(Vec::new())
-        .len();
-    let message_limit = GLOBAL_CONFIG
-        .lock()
-        .unwrap()
-        .clone()
-        .unwrap()
-        .message_limit
-        .unwrap_or(u64::max_value());
-    let room_size_limit = GLOBAL_CONFIG
+/// Get the context budget for the model, or for the backend's default model if none is selected
+async fn context_budget(model: &Option<String>) -> Option<usize> {
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    let model = match model {
+        Some(model) => Some(model.clone()),
+        None => get_backend().default_model().await.ok(),
+    };
+    model::context_tokens(&model, &config.models, config.context_tokens)
+}
+
+/// Drop the oldest messages that don't fit in the context budget of the model
+/// Returns the messages that were dropped
+async fn fit_context(conversation: &mut Conversation, model: &Option<String>) -> Vec<ChatMessage> {
+    let Some(budget) = context_budget(model).await else {
+        return Vec::new();
+    };
+    let dropped = conversation.truncate(budget);
+    if !dropped.is_empty() {
+        info!(
+            "Dropped {} messages to fit in {} tokens",
+            dropped.len(),
+            budget
+        );
+    }
+    dropped
+}
+
+/// Fit the conversation in the context budget of the model
+/// The messages that don't fit are summarized into a recap, unless that is disabled
+/// Each thread is a separate conversation, so it has its own recap
+async fn fit_context_with_recap(
+    room: &Room,
+    sender: &OwnedUserId,
+    thread: &Option<OwnedEventId>,
+    conversation: &mut Conversation,
+    model: &Option<String>,
+) {
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    if !config.summarize_history.unwrap_or(true) {
+        fit_context(conversation, model).await;
+        return;
+    }
+    let Some(budget) = context_budget(model).await else {
+        return;
+    };
+    if conversation.clone().truncate(budget).is_empty() {
+        return;
+    }
+
+    // Keep using the cached recap while the messages after it still fit
+    let cached = GLOBAL_SUMMARIES
         .lock()
         .unwrap()
-        .clone()
-        .unwrap()
-        .room_size_limit
-        .unwrap_or(u64::max_value());
-    let count = {
-        let mut messages = GLOBAL_MESSAGES.lock().unwrap();
-        let count = match messages.get_mut(sender.as_str()) {
-            Some(count) => count,
-            None => {
-                // Insert the user with a val of 0 and return a mutable reference to the value
-                messages.insert(sender.as_str().to_string(), 0);
-                messages.get_mut(sender.as_str()).unwrap()
+        .get(&(room.room_id().to_owned(), thread.clone()))
+        .cloned();
+    if let Some((until, recap)) = cached {
+        if let Some(index) = conversation
+            .messages
+            .iter()
+            .position(|message| message.event_id.as_ref() == Some(&until))
+        {
+            let mut recapped = conversation.clone();
+            recapped.messages.drain(..=index);
+            recapped.summary = Some(recap);
+            if recapped.clone().truncate(budget).is_empty() {
+                *conversation = recapped;
+                return;
             }
-        };
-        // If the room is too big we will silently ignore the message
-        // This is to prevent the bot from spamming large rooms
+        }
+    }
+
+    // Cut down to half the budget, so the next several turns fit without a new recap
+    let dropped = conversation.truncate((budget / 2).saturating_sub(RECAP_TOKENS));
+    info!(
+        "Summarizing {} messages that don't fit in {} tokens",
+        dropped.len(),
+        budget
+    );
+    conversation.summary = summarize_history(room, sender, thread, dropped).await;
+}
+
+/// Rate limit the user to a set number of messages
+/// Returns true if the user is being rate limited
+async fn rate_limit(room: &Room, sender: &OwnedUserId) -> bool {
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    // If the room is too big we will silently ignore the message
+    // This is to prevent the bot from spamming large rooms
+    if let Some(room_size_limit) = config.room_size_limit.filter(|limit| *limit > 0) {
+        let room_size = room
+            .members(RoomMemberships::ACTIVE)
+            .await
+            .unwrap_or(Vec::new())
+            .len();
         if room_size as u64 > room_size_limit {
             return true;
         }
-        if *count < message_limit {
-            *count += 1;
-            return false;
-        }
-        *count
+    }
+    let result = GLOBAL_RATE_LIMITER.lock().unwrap().check(
+        sender.as_str(),
+        room.room_id().as_str(),
+        &config.rate_limits.unwrap_or_default(),
+        config.message_limit.filter(|limit| *limit > 0),
+        ratelimit::now(),
+    );
+    let Err(limited) = result else {
+        return false;
     };
-    error!("User {} has sent {} messages", sender, count);
+    error!("User {} is rate limited: {}", sender, limited);
     room.send(RoomMessageEventContent::notice_plain(format!(
-        ".error: you have used up your message limit of {} messages.",
-        message_limit
+        ".error: {}",
+        limited
     )))
     .await
     .unwrap();
@@ -420,12 +802,22 @@ async fn rate_limit(room: &Room, sender: &OwnedUserId) -> bool {
 
 /// List the available models
 async fn list_models(_: OwnedUserId, _: String, room: Room) -> Result<(), ()> {
-    let (_, current_model, _, _) = get_context(&room).await.unwrap();
-    let response = format!(
-        ".models:\n\ncurrent: {}\n\nAvailable Models:\n{}",
-        current_model.unwrap_or(get_backend().default_model()),
-        get_backend().list_models().join("\n")
-    );
+    let (_, settings) = get_context(&room, &None).await.unwrap();
+    let current_model = settings.model;
+    let backend = get_backend();
+    let models = backend.list_models().await;
+    let current_model = match current_model {
+        Some(model) => Ok(model),
+        None => backend.default_model().await,
+    };
+    let response = match (current_model, models) {
+        (Ok(current_model), Ok(models)) => format!(
+            ".models:\n\ncurrent: {}\n\nAvailable Models:\n{}",
+            current_model,
+            models.join("\n")
+        ),
+        (Err(e), _) | (_, Err(e)) => format!(".error: {}", e.replace('\n', " ")),
+    };
     room.send(RoomMessageEventContent::notice_plain(response))
         .await
         .unwrap();
@@ -437,10 +829,22 @@ async fn model(sender: OwnedUserId, text: String, room: Room) -> Result<(), ()>
     // Get the second word in the command
     let model = text.split_whitespace().nth(1);
     if let Some(model) = model {
-        let models = get_backend().list_models();
-        if models.contains(&model.to_string()) {
-            // Set the model
-            let response = format!(".model: Set to \"{}\"", model);
+        let models = match get_backend().list_models().await {
+            Ok(models) => models,
+            Err(e) => {
+                room.send(RoomMessageEventContent::notice_plain(format!(
+                    ".error: {}",
+                    e.replace('\n', " ")
+                )))
+                .await
+                .unwrap();
+                return Ok(());
+            }
+        };
+        if let Some(model) = backend::find_model(model, &models) {
+            // Set the model, as it is named in the list
+            let note = save_setting(&room, |settings| settings.model = Some(model.clone())).await;
+            let response = format!(".model: Set to \"{}\"{}", model, note);
             room.send(RoomMessageEventContent::notice_plain(response))
                 .await
                 .unwrap();
@@ -460,67 +864,638 @@ async fn model(sender: OwnedUserId, text: String, room: Room) -> Result<(), ()>
     Ok(())
 }
 
-async fn rename(sender: OwnedUserId, _: String, room: Room) -> Result<(), ()> {
+/// List the available roles
+async fn list_roles(_: OwnedUserId, _: String, room: Room) -> Result<(), ()> {
+    let (_, settings) = get_context(&room, &None).await.unwrap();
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    let current_role = settings.role.or(config.role.clone()).unwrap_or_default();
+    let roles = role::list_roles(config.roles, DEFAULT_CONFIG.roles.clone())
+        .into_iter()
+        .map(|(name, description)| match description {
+            Some(description) => format!("{} - {}", name, description),
+            None => name,
+        })
+        .collect::<Vec<String>>();
+    let response = format!(
+        ".roles:\n\ncurrent: {}\n\nAvailable Roles:\n{}",
+        current_role,
+        roles.join("\n")
+    );
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await
+        .unwrap();
+    Ok(())
+}
+
+async fn role(sender: OwnedUserId, text: String, room: Room) -> Result<(), ()> {
+    // Get the second word in the command
+    let role = text.split_whitespace().nth(1);
+    if let Some(role) = role {
+        if role_exists(role) {
+            let note = save_setting(&room, |settings| settings.role = Some(role.to_string())).await;
+            let response = format!(".role: Set to \"{}\"{}", role, note);
+            room.send(RoomMessageEventContent::notice_plain(response))
+                .await
+                .unwrap();
+        } else {
+            let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+            let roles = role::list_roles(config.roles, DEFAULT_CONFIG.roles.clone())
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<String>>();
+            let response = format!(
+                ".error: Role \"{}\" not found.\n\nAvailable roles:\n{}",
+                role,
+                roles.join("\n")
+            );
+            room.send(RoomMessageEventContent::notice_plain(response))
+                .await
+                .unwrap();
+        }
+    } else {
+        list_roles(sender, text, room).await?;
+    }
+    Ok(())
+}
+
+/// Set, show or reset the custom system prompt
+async fn system(_: OwnedUserId, text: String, room: Room) -> Result<(), ()> {
+    let response = match system_prompt_argument(&text) {
+        "" => {
+            let (_, settings) = get_context(&room, &None).await.unwrap();
+            match settings.system {
+                Some(system) => format!(".system:\n{}", system),
+                None => ".system: No custom system prompt is set".to_string(),
+            }
+        }
+        "reset" => {
+            let note = save_setting(&room, |settings| settings.system = None).await;
+            format!(".system: Reset to the role{}", note)
+        }
+        prompt => {
+            let note =
+                save_setting(&room, |settings| settings.system = Some(prompt.to_string())).await;
+            format!(".system: Set the system prompt{}", note)
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await
+        .unwrap();
+    Ok(())
+}
+
+/// Get the prompt given to a `.system` command
+/// Unlike other commands the whole rest of the message is used, including newlines
+fn system_prompt_argument(text: &str) -> &str {
+    text.trim_start().trim_start_matches(".system").trim()
+}
+
+/// Save a setting to the room state, so that it survives `.clear`
+/// Returns a note for the response if it couldn't be saved
+async fn save_setting(room: &Room, change: impl FnOnce(&mut RoomSettings)) -> String {
+    match settings::update(room, change).await {
+        Ok(()) => String::new(),
+        Err(e) => {
+            warn!("Couldn't save the room settings: {}", e);
+            "\n(I can't save room settings here, so this only lasts until the next .clear)"
+                .to_string()
+        }
+    }
+}
+
+/// Check if the role is defined in the global config or the inbuilt roles
+fn role_exists(role: &str) -> bool {
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    role::role_exists(role, config.roles, DEFAULT_CONFIG.roles.clone())
+}
+
+/// Send the same prompt to several models at once, and post each answer as it arrives
+async fn compare(sender: OwnedUserId, text: String, room: Room) -> Result<(), ()> {
     if rate_limit(&room, &sender).await {
         return Ok(());
     }
-    if let Ok((context, _, _, _)) = get_context(&room).await {
-        let title_prompt= [
-                            &context,
-                            "\nUSER: Summarize this conversation in less than 20 characters to use as the title of this conversation. ",
-                            "The output should be a single line of text describing the conversation. ",
-                            "Do not output anything except for the summary text. ",
-                            "Only the first 20 characters will be used. ",
-                            "\nASSISTANT: ",
-                        ].join("");
-        let model = get_chat_summary_model();
+    let models = match get_backend().list_models().await {
+        Ok(models) => models,
+        Err(e) => {
+            room.send(RoomMessageEventContent::notice_plain(format!(
+                ".error: {}",
+                e.replace('\n', " ")
+            )))
+            .await
+            .unwrap();
+            return Ok(());
+        }
+    };
 
+    // The leading words that name models are the models to compare, the rest is the prompt
+    let mut input = text
+        .trim_start()
+        .trim_start_matches(".compare")
+        .trim_start();
+    let mut selected = Vec::new();
+    while let Some(word) = input.split_whitespace().next() {
+        let Some(model) = backend::find_model(word, &models) else {
+            break;
+        };
+        selected.push(model);
+        input = input[word.len()..].trim_start();
+    }
+    let input = input.trim();
+    if selected.is_empty() || input.is_empty() {
+        room.send(RoomMessageEventContent::notice_plain(format!(
+            ".error: Usage: .compare <model> <model>... <message>\n\nAvailable models:\n{}",
+            models.join("\n")
+        )))
+        .await
+        .unwrap();
+        return Ok(());
+    }
+
+    info!(
+        "Compare: {} - {} - {}",
+        sender.as_str(),
+        selected.join(", "),
+        input.replace('\n', " ")
+    );
+    let mut requests = JoinSet::new();
+    for model in selected {
+        let room = room.clone();
+        let backend = get_metered_backend(&room, &sender);
+        let conversation = Conversation::from_prompt(input.to_string());
+        requests.spawn(async move {
+            let start = Instant::now();
+            let result = backend
+                .execute(&Some(model.clone()), &conversation)
+                .await
+                .map(|completion| completion.text);
+            let elapsed = start.elapsed().as_secs_f64();
+            let response = match result {
+                Ok(result) => {
+                    info!(
+                        "Compare response: {} - {}",
+                        model,
+                        result.replace('\n', " ")
+                    );
+                    format!(".compare: {} ({:.1}s):\n{}", model, elapsed, result)
+                }
+                Err(stderr) => {
+                    error!("Compare error: {} - {}", model, stderr.replace('\n', " "));
+                    format!(
+                        ".error: {} ({:.1}s): {}",
+                        model,
+                        elapsed,
+                        stderr.replace('\n', " ")
+                    )
+                }
+            };
+            room.send(RoomMessageEventContent::notice_markdown(response))
+                .await
+                .unwrap();
+        });
+    }
+    typing::while_typing(&room, async {
+        while requests.join_next().await.is_some() {}
+    })
+    .await;
+    Ok(())
+}
+
+/// Show the sender's usage and remaining quota, or everyone's usage for admins
+async fn usage(sender: OwnedUserId, text: String, room: Room) -> Result<(), ()> {
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    let all = text.split_whitespace().nth(1) == Some("all");
+    if all && !is_admin(&sender) {
+        room.send(RoomMessageEventContent::notice_plain(
+            ".error: Only admins can see everyone's usage",
+        ))
+        .await
+        .unwrap();
+        return Ok(());
+    }
+
+    let mut response = String::from(".usage:\n");
+    if all {
+        let totals = GLOBAL_USAGE.lock().unwrap().all_user_totals();
+        if totals.is_empty() {
+            response.push_str("No usage recorded yet\n");
+        }
+        for (user, models) in totals {
+            let messages = GLOBAL_RATE_LIMITER.lock().unwrap().total(&user);
+            response.push_str(&format!("{}: {} messages\n", user, messages));
+            response.push_str(&model_usage(&models));
+        }
+    } else {
+        let user = sender.as_str();
+        let room_id = room.room_id().as_str();
+        let messages = GLOBAL_RATE_LIMITER.lock().unwrap().total(user);
+        let models = GLOBAL_USAGE.lock().unwrap().user_totals(user);
+        response.push_str(&format!("Messages: {}\n", messages));
+        response.push_str(&model_usage(&models));
+
+        let mut remaining = Vec::new();
+        if let Some(limit) = config.message_limit.filter(|limit| *limit > 0) {
+            remaining.push(format!(
+                "{} of {} messages in total for you",
+                limit.saturating_sub(messages),
+                limit
+            ));
+        }
+        remaining.extend(GLOBAL_RATE_LIMITER.lock().unwrap().remaining(
+            user,
+            room_id,
+            &config.rate_limits.unwrap_or_default(),
+            ratelimit::now(),
+        ));
+        remaining.extend(GLOBAL_USAGE.lock().unwrap().remaining(
+            user,
+            room_id,
+            &config.usage_limits.unwrap_or_default(),
+        ));
+        if remaining.is_empty() {
+            response.push_str("Remaining: unlimited\n");
+        } else {
+            response.push_str("Remaining:\n");
+            for line in remaining {
+                response.push_str(&format!("  {}\n", line));
+            }
+        }
+    }
+    room.send(RoomMessageEventContent::notice_plain(response.trim_end()))
+        .await
+        .unwrap();
+    Ok(())
+}
+
+/// Describe the usage of each model, sorted by model name
+fn model_usage(models: &HashMap<String, Tally>) -> String {
+    let mut models: Vec<_> = models.iter().collect();
+    models.sort_by(|a, b| a.0.cmp(b.0));
+    models
+        .into_iter()
+        .map(|(model, tally)| format!("  {}: {}\n", model, tally))
+        .collect()
+}
+
+/// Regenerate the last answer from the bot, and replace it with the new one
+async fn regen(sender: OwnedUserId, text: String, room: Room) -> Result<(), ()> {
+    if rate_limit(&room, &sender).await {
+        return Ok(());
+    }
+
+    // Use the model given with the command, otherwise the one for the room
+    let mut model = None;
+    if let Some(name) = text.split_whitespace().nth(1) {
+        let models = get_backend().list_models().await;
+        model = models
+            .ok()
+            .and_then(|models| backend::find_model(name, &models));
+        if model.is_none() {
+            room.send(RoomMessageEventContent::notice_plain(format!(
+                ".error: Model \"{}\" not found.",
+                name
+            )))
+            .await
+            .unwrap();
+            return Ok(());
+        }
+    }
+    regenerate(&room, &sender, None, model).await;
+    Ok(())
+}
+
+/// Regenerate an answer from the bot, and replace it with the new one
+/// Regenerates the last answer if `answer` isn't given, and uses the model for the room if `model` isn't
+async fn regenerate(
+    room: &Room,
+    sender: &OwnedUserId,
+    answer: Option<OwnedEventId>,
+    model: Option<String>,
+) {
+    // Answers in a thread are regenerated from the thread
+    let thread = match &answer {
+        Some(answer) => history::event(room, answer)
+            .await
+            .and_then(|event| {
+                event
+                    .get_field::<RoomMessageEventContent>("content")
+                    .unwrap_or(None)
+            })
+            .and_then(|content| thread_root(&content)),
+        None => None,
+    };
+    let Ok((mut conversation, settings)) = get_context(room, &thread).await else {
+        return;
+    };
+    let model = model.or(settings.model.clone());
+
+    // Drop the answer, and anything after it, so the context ends with the message it answered
+    let index = conversation
+        .messages
+        .iter()
+        .rposition(|message| match &answer {
+            Some(answer) => message.event_id.as_ref() == Some(answer),
+            None => message.role == MessageRole::Assistant,
+        })
+        .filter(|index| conversation.messages[*index].role == MessageRole::Assistant);
+    let Some((index, event_id)) = index.and_then(|index| {
+        let event_id = conversation.messages[index].event_id.clone()?;
+        Some((index, event_id))
+    }) else {
+        room.send(RoomMessageEventContent::notice_plain(
+            ".error: There is no answer to regenerate",
+        ))
+        .await
+        .unwrap();
+        return;
+    };
+    conversation.messages.truncate(index);
+
+    add_role(&mut conversation, &settings);
+    let result = typing::while_typing(room, async {
+        fit_context_with_recap(room, sender, &thread, &mut conversation, &model).await;
         info!(
-            "Request: {} - {}",
+            "Regenerate: {} - {}",
             sender.as_str(),
-            title_prompt.replace('\n', " ")
+            conversation.full_transcript().replace('\n', " ")
         );
-        let response = get_backend().execute(&model, title_prompt, Vec::new());
-        if let Ok(result) = response {
-            info!(
-                "Response: {} - {}",
-                sender.as_str(),
-                result.replace('\n', " ")
-            );
-            let result = clean_summary_response(&result, None);
-            if room.set_name(result).await.is_err() {
-                room.send(RoomMessageEventContent::notice_plain(
-                    ".error: I don't have permission to rename the room",
-                ))
+        get_metered_backend(room, sender)
+            .execute(&model, &conversation)
+            .await
+            .map(|completion| completion.text)
+    })
+    .await;
+    match result {
+        Ok(result) => {
+            info!("Response: {}", result.replace('\n', " "));
+            // The old answer may have been split, the rest of it is replaced by the new pieces
+            for continuation in continuations(room, &event_id).await {
+                if let Err(e) = room.redact(&continuation, None, None).await {
+                    error!("Error removing the old answer: {}", e);
+                }
+            }
+
+            let chunks = split::split_message(&result);
+            let content = RoomMessageEventContent::text_markdown(&chunks[0])
+                .make_replacement(ReplacementMetadata::new(event_id.clone(), None), None);
+            room.send(content).await.unwrap();
+            if let Err(e) = split::send_chain(room, event_id, &thread, &chunks[1..]).await {
+                error!("Error sending the rest of the answer: {}", e);
+            }
+        }
+        Err(stderr) => {
+            error!("Error: {}", stderr.replace('\n', " "));
+            room.send(RoomMessageEventContent::notice_plain(format!(
+                ".error: {}",
+                stderr.replace('\n', " ")
+            )))
+            .await
+            .unwrap();
+        }
+    }
+}
+
+/// Check if the message mentions the bot, or is a reply to one of its messages
+async fn mentions_bot(room: &Room, event: &OriginalSyncRoomMessageEvent) -> bool {
+    let Some(bot_id) = room.client().user_id().map(|uid| uid.to_owned()) else {
+        return false;
+    };
+    if event
+        .content
+        .mentions
+        .as_ref()
+        .is_some_and(|mentions| mentions.user_ids.contains(&bot_id))
+    {
+        return true;
+    }
+
+    // Older clients only mention by name, or with a pill in the formatted body
+    let body = event.content.body().to_lowercase();
+    if body.contains(&bot_id.as_str().to_lowercase()) {
+        return true;
+    }
+    if let Ok(Some(member)) = room.get_member_no_sync(&bot_id).await {
+        if member
+            .display_name()
+            .is_some_and(|name| body.contains(&name.to_lowercase()))
+        {
+            return true;
+        }
+    }
+    if let MessageType::Text(text_content) = &event.content.msgtype {
+        if text_content
+            .formatted
+            .as_ref()
+            .is_some_and(|formatted| formatted.body.contains(bot_id.as_str()))
+        {
+            return true;
+        }
+    }
+
+    // Replies to the bot count as mentioning it
+    let in_reply_to = match &event.content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => Some(in_reply_to.event_id.clone()),
+        // Thread messages reply to the previous message in the thread unless they're a real reply
+        Some(Relation::Thread(thread)) if !thread.is_falling_back => thread
+            .in_reply_to
+            .as_ref()
+            .map(|in_reply_to| in_reply_to.event_id.clone()),
+        _ => None,
+    };
+    if let Some(in_reply_to) = in_reply_to {
+        if let Some(replied) = history::event(room, &in_reply_to).await {
+            return replied
+                .get_field::<String>("sender")
+                .unwrap_or(None)
+                .as_deref()
+                == Some(bot_id.as_str());
+        }
+    }
+    false
+}
+
+/// Regenerate the answer to a message that was edited, or offer to if that isn't enabled
+async fn respond_to_edit(
+    room: &Room,
+    sender: &OwnedUserId,
+    question: &OwnedEventId,
+) -> Result<String, String> {
+    let Some(answer) = find_answer(room, question).await else {
+        return Ok("edited message was not answered".to_string());
+    };
+    // Edits are ignored in the same rooms new messages would be
+    let Ok((_, settings)) = get_context(room, &None).await else {
+        return Err("could not get context".to_string());
+    };
+    if settings.lurk.unwrap_or(false) {
+        return Ok("lurking".to_string());
+    }
+    if settings.mention.unwrap_or(false) {
+        // The edit only lists new mentions, so check the message that was edited
+        let original = history::event(room, question)
+            .await
+            .and_then(|event| event.deserialize_as::<OriginalSyncRoomMessageEvent>().ok());
+        let mentioned = match original {
+            Some(original) => mentions_bot(room, &original).await,
+            None => false,
+        };
+        if !mentioned {
+            return Ok("not mentioned".to_string());
+        }
+    }
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    if config.regenerate_on_edit.unwrap_or(false) {
+        if rate_limit(room, sender).await {
+            return Ok("rate limited".to_string());
+        }
+        regenerate(room, sender, Some(answer), None).await;
+        Ok("regenerated after edit".to_string())
+    } else {
+        room.send(RoomMessageEventContent::notice_plain(
+            ".edit: The message was edited, react with 🔁 to the answer to regenerate it",
+        ))
+        .await
+        .unwrap();
+        Ok("offered to regenerate after edit".to_string())
+    }
+}
+
+/// Find the pieces of a split answer that follow the first one, oldest first
+async fn continuations(room: &Room, answer: &OwnedEventId) -> Vec<OwnedEventId> {
+    let mut continuations = Vec::new();
+    let mut previous = answer.clone();
+    while let Some(next) = find_answer(room, &previous).await {
+        continuations.push(next.clone());
+        previous = next;
+    }
+    continuations
+}
+
+/// Find the newest answer from the bot to a message
+async fn find_answer(room: &Room, question: &OwnedEventId) -> Option<OwnedEventId> {
+    let bot_id = room.client().user_id().map(|uid| uid.to_string());
+    history::events(room).await.into_iter().find_map(|event| {
+        if event.get_field::<String>("sender").unwrap_or(None) != bot_id {
+            return None;
+        }
+        let content = event
+            .get_field::<RoomMessageEventContent>("content")
+            .unwrap_or(None)?;
+        if reply_target(&content)? == *question {
+            event.get_field::<OwnedEventId>("event_id").unwrap_or(None)
+        } else {
+            None
+        }
+    })
+}
+
+/// Act on a reaction to one of the bot's answers
+/// 🔁 regenerates the answer, ❌ removes it, and 📌 pins it so it's kept after `.clear`
+async fn react(
+    sender: OwnedUserId,
+    key: String,
+    answer: OwnedEventId,
+    room: Room,
+) -> Result<String, String> {
+    let Some(event) = history::event(&room, &answer).await else {
+        return Err(format!("could not read {}", answer));
+    };
+    let bot_id = room.client().user_id().map(|uid| uid.to_string());
+    if event.get_field::<String>("sender").unwrap_or(None) != bot_id {
+        return Ok("not reacting to other users".to_string());
+    }
+    match key.trim_end_matches('\u{fe0f}') {
+        "🔁" => {
+            if rate_limit(&room, &sender).await {
+                return Ok("rate limited".to_string());
+            }
+            regenerate(&room, &sender, Some(answer), None).await;
+            Ok("regenerated".to_string())
+        }
+        "❌" => {
+            // A long answer is split over several messages, they're all removed
+            for continuation in continuations(&room, &answer).await {
+                room.redact(&continuation, Some("Removed by reaction"), None)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            room.redact(&answer, Some("Removed by reaction"), None)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok("removed".to_string())
+        }
+        "📌" => {
+            // A long answer is split over several messages, they're all pinned
+            let mut pieces = vec![answer.clone()];
+            pieces.extend(continuations(&room, &answer).await);
+            if let Err(e) = pins::pin(&room, pieces).await {
+                room.send(RoomMessageEventContent::notice_plain(format!(
+                    ".error: Couldn't pin the message: {}",
+                    e.replace('\n', " ")
+                )))
                 .await
                 .unwrap();
+                return Err(e);
+            }
+            Ok("pinned".to_string())
+        }
+        _ => Ok("ignoring reaction".to_string()),
+    }
+}
 
-                // If we can't set the name, we can't set the topic either
-                return Ok(());
+async fn rename(sender: OwnedUserId, _: String, room: Room) -> Result<(), ()> {
+    if rate_limit(&room, &sender).await {
+        return Ok(());
+    }
+    typing::while_typing(&room, rename_room(&room, &sender)).await;
+    Ok(())
+}
+
+/// Set the room name and topic from summaries of the conversation
+/// The summaries count towards the usage of the sender
+async fn rename_room(room: &Room, sender: &OwnedUserId) {
+    if let Ok((mut conversation, _)) = get_context(room, &None).await {
+        let model = get_chat_summary_model();
+        fit_context(&mut conversation, &model).await;
+
+        let title_prompt = [
+            "Summarize this conversation in less than 20 characters to use as the title of this conversation. ",
+            "The output should be a single line of text describing the conversation. ",
+            "Do not output anything except for the summary text. ",
+            "Only the first 20 characters will be used. ",
+        ]
+        .join("");
+        match summarize(room, sender, &conversation, &model, &title_prompt).await {
+            Ok(result) => {
+                let result = clean_summary_response(&result, None);
+                if room.set_name(result).await.is_err() {
+                    room.send(RoomMessageEventContent::notice_plain(
+                        ".error: I don't have permission to rename the room",
+                    ))
+                    .await
+                    .unwrap();
+
+                    // If we can't set the name, we can't set the topic either
+                    return;
+                }
+            }
+            Err(e) => {
+                room.send(RoomMessageEventContent::notice_plain(format!(
+                    ".error: {}",
+                    e.replace('\n', " ")
+                )))
+                .await
+                .unwrap();
+                return;
             }
         }
 
         let topic_prompt = [
-            &context,
-            "\nUSER: Summarize this conversation in less than 50 characters. ",
+            "Summarize this conversation in less than 50 characters. ",
             "Do not output anything except for the summary text. ",
             "Do not include any commentary or context, only the summary. ",
-            "\nASSISTANT: ",
         ]
         .join("");
-
-        info!(
-            "Request: {} - {}",
-            sender.as_str(),
-            topic_prompt.replace('\n', " ")
-        );
-        let response = get_backend().execute(&model, topic_prompt, Vec::new());
-        if let Ok(result) = response {
-            info!(
-                "Response: {} - {}",
-                sender.as_str(),
-                result.replace('\n', " ")
-            );
+        if let Ok(result) = summarize(room, sender, &conversation, &model, &topic_prompt).await {
             let result = clean_summary_response(&result, None);
             if room.set_room_topic(&result).await.is_err() {
                 room.send(RoomMessageEventContent::notice_plain(
@@ -531,13 +1506,102 @@ async fn rename(sender: OwnedUserId, _: String, room: Room) -> Result<(), ()> {
             }
         }
     }
-    Ok(())
+}
+
+/// Ask the model to summarize the conversation
+/// The instruction is sent as the last message from the user
+/// The summary counts towards the usage of the sender
+async fn summarize(
+    room: &Room,
+    sender: &OwnedUserId,
+    conversation: &Conversation,
+    model: &Option<String>,
+    instruction: &str,
+) -> Result<String, String> {
+    let mut prompt = conversation.clone();
+    prompt.push(ChatMessage::new(MessageRole::User, instruction.to_string()));
+
+    info!(
+        "Summary request: {}",
+        prompt.full_transcript().replace('\n', " ")
+    );
+    let response = get_metered_backend(room, sender)
+        .execute(model, &prompt)
+        .await
+        .map(|completion| completion.text);
+    match &response {
+        Ok(result) => info!("Summary response: {}", result.replace('\n', " ")),
+        Err(e) => error!("Summary error: {}", e.replace('\n', " ")),
+    }
+    response
+}
+
+/// Summarize the messages that were dropped from the context into a recap
+/// The recap is cached per room and thread, and only extended when more messages are dropped
+async fn summarize_history(
+    room: &Room,
+    sender: &OwnedUserId,
+    thread: &Option<OwnedEventId>,
+    dropped: Vec<ChatMessage>,
+) -> Option<String> {
+    let until = dropped.last()?.event_id.clone()?;
+    let cached = GLOBAL_SUMMARIES
+        .lock()
+        .unwrap()
+        .get(&(room.room_id().to_owned(), thread.clone()))
+        .cloned();
+
+    let mut history = Conversation::default();
+    let mut start = 0;
+    if let Some((summarized, recap)) = cached {
+        // Only the messages after the cached recap need to be summarized
+        if let Some(index) = dropped
+            .iter()
+            .position(|message| message.event_id.as_ref() == Some(&summarized))
+        {
+            if index + 1 == dropped.len() {
+                return Some(recap);
+            }
+            start = index + 1;
+            history.summary = Some(recap);
+        }
+    }
+    history.messages = dropped[start..].to_vec();
+
+    let model = get_chat_summary_model();
+    fit_context(&mut history, &model).await;
+    let instruction = [
+        "Summarize this conversation in less than 150 words. ",
+        "Include any details that are needed to continue the conversation. ",
+        "Do not output anything except for the summary text. ",
+    ]
+    .join("");
+    let recap = summarize(room, sender, &history, &model, &instruction)
+        .await
+        .ok()?;
+    GLOBAL_SUMMARIES.lock().unwrap().insert(
+        (room.room_id().to_owned(), thread.clone()),
+        (until, recap.clone()),
+    );
+    Some(recap)
 }
 
 /// Returns the backend based on the global config
-fn get_backend() -> AiChat {
+fn get_backend() -> Arc<dyn Backend> {
+    GLOBAL_BACKEND.clone()
+}
+
+/// Returns the backend for a request from the user, which checks and records their usage
+fn get_metered_backend(room: &Room, sender: &OwnedUserId) -> Box<dyn Backend> {
     let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
-    AiChat::new("aichat".to_string(), config.aichat_config_dir.clone())
+    Box::new(Metered::new(
+        get_backend(),
+        GLOBAL_USAGE.clone(),
+        sender.to_string(),
+        room.room_id().to_string(),
+        config.usage_limits.unwrap_or_default(),
+        config.models,
+    ))
 }
 
 /// Try to clean up the response from the model containing a summary
@@ -560,171 +1624,337 @@ fn clean_summary_response(response: &str, max_length: Option<usize>) -> String {
     response.to_string()
 }
 
+/// Get the streaming config, streaming is disabled if it isn't set
+fn get_streaming_config() -> Option<StreamingConfig> {
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    config.streaming
+}
+
+/// Get how messages are attributed to their senders from the global config
+fn get_speaker_labels() -> SpeakerLabels {
+    let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
+    config.speaker_labels.unwrap_or_default()
+}
+
+/// Get the label for a user in the room
+/// Falls back to the user ID if they don't have a display name
+async fn speaker_label(room: &Room, sender: &str, speaker_labels: SpeakerLabels) -> String {
+    if speaker_labels == SpeakerLabels::DisplayName {
+        if let Ok(user_id) = OwnedUserId::try_from(sender) {
+            if let Ok(Some(member)) = room.get_member_no_sync(&user_id).await {
+                if let Some(display_name) = member.display_name() {
+                    return display_name.to_string();
+                }
+            }
+        }
+    }
+    sender.to_string()
+}
+
+/// Get the state directory from the global config
+/// Defaults to $XDG_STATE_HOME/chaz
+fn get_state_dir(config: &Config) -> Option<PathBuf> {
+    match &config.state_dir {
+        Some(state_dir) => Some(PathBuf::from(state_dir)),
+        None => dirs::state_dir().map(|dir| dir.join("chaz")),
+    }
+}
+
 /// Get the chat summary model from the global config
 fn get_chat_summary_model() -> Option<String> {
     let config = GLOBAL_CONFIG.lock().unwrap().clone().unwrap();
     config.chat_summary_model
 }
 
+/// Get the root of the thread that the message is in, if it's in one
+fn thread_root(content: &RoomMessageEventContent) -> Option<OwnedEventId> {
+    match &content.relates_to {
+        Some(Relation::Thread(thread)) => Some(thread.event_id.clone()),
+        _ => None,
+    }
+}
+
+/// Get the message that the message replies to, if it's a reply
+fn reply_target(content: &RoomMessageEventContent) -> Option<OwnedEventId> {
+    match &content.relates_to {
+        Some(Relation::Reply { in_reply_to }) => Some(in_reply_to.event_id.clone()),
+        Some(Relation::Thread(thread)) => thread
+            .in_reply_to
+            .as_ref()
+            .map(|in_reply_to| in_reply_to.event_id.clone()),
+        _ => None,
+    }
+}
+
 /// Gets the context of the current conversation
-/// Returns a model if it was ever entered
+/// Threads are separate conversations, so with a thread root only that thread is read,
+/// and without one only the messages outside of threads are read
+/// Returns the settings for the room, from the room state or the commands that were entered
 async fn get_context(
     room: &Room,
-) -> Result<(String, Option<String>, Option<bool>, Vec<MediaFileHandle>), ()> {
-    // Read all the messages in the room, place them into a single string, and print them out
+    thread: &Option<OwnedEventId>,
+) -> Result<(Conversation, RoomSettings), ()> {
+    // Read all the messages in the room, newest first
+    // The events come from the cached history, so this doesn't need to go to the server
     let mut messages = Vec::new();
 
-    let mut options = MessagesOptions::backward();
-    let mut model_response = None;
-    let mut lurk = None;
-    let mut media = Vec::new();
+    let mut settings = RoomSettings::default();
+    // Only the newest `.system` command counts, even if it was a reset
+    let mut system_seen = false;
+    // The newest edit of each message, keyed by the id of the original message
+    let mut edits: HashMap<OwnedEventId, (String, MessageType)> = HashMap::new();
+    let bot_id = room.client().user_id().map(|uid| uid.to_string());
+    // Answers from the bot that were removed, the rest of their chain is removed with them
+    let mut removed: HashSet<OwnedEventId> = HashSet::new();
 
-    'outer: while let Ok(batch) = room.messages(options).await {
-        // This assumes that the messages are in reverse order
-        for message in batch.chunk {
-            if let Some((sender, content)) = message
-                .event
-                .get_field::<String>("sender")
-                .unwrap_or(None)
-                .zip(
-                    message
-                        .event
-                        .get_field::<RoomMessageEventContent>("content")
-                        .unwrap_or(None),
-                )
-            {
-                match &content.msgtype {
-                    MessageType::Audio(audio_content) => {
-                        messages.push(format!("USER sent an audio file: {}\n", audio_content.body));
-                    }
-                    MessageType::Emote(emote_content) => {
-                        // USER sent an emote: sends hearts 💝
-                        messages.push(format!("USER sent an emote: {}\n", emote_content.body));
-                    }
-                    MessageType::File(file_content) => {
-                        messages.push(format!("USER sent a file: {}\n", file_content.body));
-                        let request = MediaRequest {
-                            source: file_content.source.clone(),
-                            format: MediaFormat::File,
-                        };
-                        let mime = file_content
-                            .info
-                            .as_ref()
-                            .unwrap()
-                            .mimetype
-                            .clone()
-                            .unwrap()
-                            .parse()
-                            .unwrap();
-                        let x = room
-                            .client()
-                            .media()
-                            .get_media_file(&request, None, &mime, true, None)
-                            .await
-                            .unwrap();
-                        media.insert(0, x);
-                    }
-                    MessageType::Image(image_content) => {
-                        messages.push(format!("USER sent an image: {}\n", image_content.body));
-                        let request = MediaRequest {
-                            source: image_content.source.clone(),
-                            format: MediaFormat::File,
-                        };
-                        let mime = image_content
-                            .info
-                            .as_ref()
-                            .unwrap()
-                            .mimetype
-                            .clone()
-                            .unwrap()
-                            .parse()
-                            .unwrap();
-                        let x = room
-                            .client()
-                            .media()
-                            .get_media_file(&request, None, &mime, true, None)
-                            .await
-                            .unwrap();
-                        media.insert(0, x);
-                    }
-                    MessageType::Location(location_content) => {
-                        messages.push(format!(
-                            "USER sent their location: {}\n",
-                            location_content.body
-                        ));
-                    }
-                    MessageType::Notice(notice_content) => {
-                        if sender != room.client().user_id().unwrap().as_str() {
-                            messages.push(format!("USER sent a notice: {}\n", notice_content.body));
-                        }
-                    }
-                    MessageType::ServerNotice(text_content) => {
-                        messages.push(format!("SERVER: {}\n", text_content.body));
+    let mut events = history::events(room).await;
+    // Pinned messages are kept even if they're from before the clear, so they're the oldest
+    events.extend(pins::pinned_events(room, &events).await);
+
+    for event in events {
+        // Redacted messages have been removed, so they shouldn't be in the context either
+        // Their content is emptied, so this has to be checked before the content is read
+        if event
+            .get_field::<serde_json::Value>("unsigned")
+            .unwrap_or(None)
+            .is_some_and(|unsigned| unsigned.get("redacted_because").is_some())
+        {
+            if event.get_field::<String>("sender").unwrap_or(None) == bot_id {
+                removed.extend(event.get_field::<OwnedEventId>("event_id").unwrap_or(None));
+            }
+            continue;
+        }
+        if let Some((sender, mut content)) =
+            event.get_field::<String>("sender").unwrap_or(None).zip(
+                event
+                    .get_field::<RoomMessageEventContent>("content")
+                    .unwrap_or(None),
+            )
+        {
+            // Edits are applied to the original message instead of being read on their own
+            // We're reading backwards, so the first edit we see is the newest
+            if let Some(Relation::Replacement(replacement)) = &content.relates_to {
+                edits
+                    .entry(replacement.event_id.clone())
+                    .or_insert((sender, replacement.new_content.msgtype.clone()));
+                continue;
+            }
+            let event_id = event.get_field::<OwnedEventId>("event_id").unwrap_or(None);
+            if let Some((editor, msgtype)) = event_id.as_ref().and_then(|id| edits.remove(id)) {
+                // Only the original sender can edit a message
+                if editor == sender {
+                    content.msgtype = msgtype;
+                }
+            }
+            // Messages in other conversations are skipped, but commands apply to the whole room
+            let in_conversation =
+                thread_root(&content) == *thread || (thread.is_some() && event_id == *thread);
+            let command = matches!(&content.msgtype, MessageType::Text(text_content) if is_command(&text_content.body));
+            if !in_conversation && !command {
+                continue;
+            }
+            let from_bot = bot_id.as_deref() == Some(sender.as_str());
+            let mut chat_message = ChatMessage {
+                role: if from_bot {
+                    MessageRole::Assistant
+                } else {
+                    MessageRole::User
+                },
+                sender: Some(sender),
+                label: None,
+                text: String::new(),
+                action: true,
+                attachments: Vec::new(),
+                reply_to: reply_target(&content),
+                event_id,
+            };
+            match &content.msgtype {
+                MessageType::Audio(audio_content) => {
+                    chat_message.text = format!("sent an audio file: {}", audio_content.body);
+                }
+                MessageType::Emote(emote_content) => {
+                    // USER sent an emote: sends hearts 💝
+                    chat_message.text = format!("sent an emote: {}", emote_content.body);
+                }
+                MessageType::File(file_content) => {
+                    chat_message.text = format!("sent a file: {}", file_content.body);
+                    let mimetype = file_content
+                        .info
+                        .as_ref()
+                        .and_then(|info| info.mimetype.as_deref());
+                    chat_message.attachments.extend(
+                        history::media(
+                            room,
+                            chat_message.event_id.as_deref(),
+                            &file_content.source,
+                            mimetype,
+                        )
+                        .await,
+                    );
+                }
+                MessageType::Image(image_content) => {
+                    chat_message.text = format!("sent an image: {}", image_content.body);
+                    let mimetype = image_content
+                        .info
+                        .as_ref()
+                        .and_then(|info| info.mimetype.as_deref());
+                    chat_message.attachments.extend(
+                        history::media(
+                            room,
+                            chat_message.event_id.as_deref(),
+                            &image_content.source,
+                            mimetype,
+                        )
+                        .await,
+                    );
+                }
+                MessageType::Location(location_content) => {
+                    chat_message.text = format!("sent their location: {}", location_content.body);
+                }
+                MessageType::Notice(notice_content) => {
+                    if from_bot {
+                        continue;
                     }
-                    MessageType::Text(text_content) => {
-                        if is_command(&text_content.body) {
-                            // if the message is a valid model command, set the model
-                            if text_content.body.starts_with(".model") && model_response.is_none() {
-                                let model = text_content.body.split_whitespace().nth(1);
-                                if let Some(model) = model {
-                                    // Add the config_dir from the global config
-                                    let models = get_backend().list_models();
-                                    if models.contains(&model.to_string()) {
-                                        model_response = Some(model.to_string());
-                                    }
-                                }
-                            } else if text_content.body.starts_with(".nolurk") {
-                                lurk = Some(false);
-                            } else if text_content.body.starts_with(".lurk") && lurk.is_none() {
-                                lurk = Some(true);
-                            } else if text_content.body.starts_with(".clear") {
-                                // if the message was a clear command, we are finished
-                                break 'outer;
+                    chat_message.text = format!("sent a notice: {}", notice_content.body);
+                }
+                MessageType::ServerNotice(text_content) => {
+                    chat_message.role = MessageRole::Server;
+                    chat_message.action = false;
+                    chat_message.text = text_content.body.clone();
+                }
+                MessageType::Text(text_content) => {
+                    if is_command(&text_content.body) {
+                        // if the message is a valid model command, set the model
+                        if text_content.body.starts_with(".model") && settings.model.is_none() {
+                            let model = text_content.body.split_whitespace().nth(1);
+                            if let Some(model) = model {
+                                // Add the config_dir from the global config
+                                let models = get_backend().list_models().await;
+                                settings.model = models
+                                    .ok()
+                                    .and_then(|models| backend::find_model(model, &models));
+                            }
+                        } else if text_content.body.split_whitespace().next() == Some(".role")
+                            && settings.role.is_none()
+                        {
+                            // if the message is a valid role command, set the role
+                            let role = text_content.body.split_whitespace().nth(1);
+                            if let Some(role) = role.filter(|role| role_exists(role)) {
+                                settings.role = Some(role.to_string());
                             }
-                        } else if !lurk.unwrap_or(false) {
-                            // Push the sender and message to the front of the string
-                            if room
-                                .client()
-                                .user_id()
-                                .is_some_and(|uid| sender == uid.as_str())
-                            {
-                                // If the sender is the bot, prefix the message with "ASSISTANT: "
-                                messages.push(format!("ASSISTANT: {}\n", text_content.body));
-                            } else {
-                                // Otherwise, prefix the message with "USER: "
-                                messages.push(format!("USER: {}\n", text_content.body));
+                        } else if text_content.body.split_whitespace().next() == Some(".system")
+                            && !system_seen
+                        {
+                            // `.system` on its own only shows the prompt
+                            let prompt = system_prompt_argument(&text_content.body);
+                            if !prompt.is_empty() {
+                                system_seen = true;
+                                if prompt != "reset" {
+                                    settings.system = Some(prompt.to_string());
+                                }
                             }
+                        } else if text_content.body.starts_with(".nomention")
+                            && settings.mention.is_none()
+                        {
+                            settings.mention = Some(false);
+                        } else if text_content.body.starts_with(".mention")
+                            && settings.mention.is_none()
+                        {
+                            settings.mention = Some(true);
+                        } else if text_content.body.starts_with(".nolurk") {
+                            settings.lurk = Some(false);
+                        } else if text_content.body.starts_with(".lurk") && settings.lurk.is_none()
+                        {
+                            settings.lurk = Some(true);
+                        } else if text_content.body.starts_with(".clear") {
+                            // The history ends at the clear command, only pinned messages come after it
+                            continue;
                         }
+                        continue;
+                    } else if settings.lurk.unwrap_or(false) {
+                        continue;
                     }
-                    // not useful information
-                    MessageType::VerificationRequest(_) => {}
-                    MessageType::Video(video_content) => {
-                        messages.push(format!("USER sent a video file: {}\n", video_content.body));
-                    }
-                    MessageType::_Custom(_) => {
-                        messages.push(format!(
-                            "USER sent a message of type {}: {}\n",
-                            content.msgtype(),
-                            content.body()
-                        ));
-                    }
-                    x => {
-                        warn!("Unhandled message type: {:#?}", x);
-                    }
-                };
+                    chat_message.action = false;
+                    chat_message.text = text_content.body.clone();
+                }
+                // not useful information
+                MessageType::VerificationRequest(_) => continue,
+                MessageType::Video(video_content) => {
+                    chat_message.text = format!("sent a video file: {}", video_content.body);
+                }
+                MessageType::_Custom(_) => {
+                    chat_message.text = format!(
+                        "sent a message of type {}: {}",
+                        content.msgtype(),
+                        content.body()
+                    );
+                }
+                x => {
+                    warn!("Unhandled message type: {:#?}", x);
+                    continue;
+                }
+            };
+            messages.push(chat_message);
+        }
+    }
+    // The messages were read newest first, so reverse them
+    messages.reverse();
+
+    // Long answers are split into a chain of replies, join them back into one answer
+    let mut joined: Vec<ChatMessage> = Vec::new();
+    for message in messages {
+        if message.role == MessageRole::Assistant
+            && message
+                .reply_to
+                .as_ref()
+                .is_some_and(|reply_to| removed.contains(reply_to))
+        {
+            removed.extend(message.event_id.clone());
+            continue;
+        }
+        if let Some(previous) = joined.last_mut() {
+            if message.role == MessageRole::Assistant
+                && previous.role == MessageRole::Assistant
+                && message.reply_to.is_some()
+                && message.reply_to == previous.event_id
+            {
+                previous.text.push_str("\n\n");
+                previous.text.push_str(&message.text);
+                continue;
             }
         }
-        if let Some(token) = batch.end {
-            options = MessagesOptions::backward().from(Some(token.as_str()));
-        } else {
-            break;
+        joined.push(message);
+    }
+    let mut messages = joined;
+
+    // Label who sent each message, so the model can tell the speakers apart
+    let speaker_labels = get_speaker_labels();
+    if speaker_labels != SpeakerLabels::None {
+        let mut labels: HashMap<String, String> = HashMap::new();
+        for message in messages
+            .iter_mut()
+            .filter(|message| message.role == MessageRole::User)
+        {
+            let Some(sender) = message.sender.clone() else {
+                continue;
+            };
+            if !labels.contains_key(&sender) {
+                let label = speaker_label(room, &sender, speaker_labels).await;
+                labels.insert(sender.clone(), label);
+            }
+            message.label = labels.get(&sender).cloned();
         }
     }
-    // Append the messages into a string with newlines in between, in reverse order
+
+    // The settings saved in the room state take priority over the commands
+    if let Some(saved) = settings::load(room).await {
+        settings = saved.or(settings);
+    }
     Ok((
-        messages.into_iter().rev().collect::<String>(),
-        model_response,
-        lurk,
-        media,
+        Conversation {
+            messages,
+            ..Default::default()
+        },
+        settings,
     ))
 }
@@ -0,0 +1,51 @@
+// Models
+// Per-model settings, looked up by the name the backend uses for the model
+
+use crate::backend::Usage;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModelDetails {
+    /// Name of the model, as listed by the backend
+    name: String,
+    /// Number of tokens of conversation that can be sent to the model
+    context_tokens: Option<usize>,
+    /// Price of a million prompt tokens, used for the usage budgets
+    prompt_price: Option<f64>,
+    /// Price of a million completion tokens, used for the usage budgets
+    completion_price: Option<f64>,
+}
+
+/// Get the model details from the model name
+fn get_model(model: &str, model_list: &Option<Vec<ModelDetails>>) -> Option<ModelDetails> {
+    model_list
+        .as_ref()?
+        .iter()
+        .find(|details| details.name == model)
+        .cloned()
+}
+
+/// Get the context budget for a model
+/// Falls back to the default budget if the model doesn't set one
+pub fn context_tokens(
+    model: &Option<String>,
+    model_list: &Option<Vec<ModelDetails>>,
+    default_tokens: Option<usize>,
+) -> Option<usize> {
+    model
+        .as_ref()
+        .and_then(|model| get_model(model, model_list))
+        .and_then(|details| details.context_tokens)
+        .or(default_tokens)
+}
+
+/// Get the cost of a request to a model
+/// Models without prices are free
+pub fn cost(model: &str, usage: &Usage, model_list: &Option<Vec<ModelDetails>>) -> f64 {
+    let Some(details) = get_model(model, model_list) else {
+        return 0.0;
+    };
+    let prompt = usage.prompt_tokens as f64 * details.prompt_price.unwrap_or(0.0);
+    let completion = usage.completion_tokens as f64 * details.completion_price.unwrap_or(0.0);
+    (prompt + completion) / 1_000_000.0
+}
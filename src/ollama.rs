@@ -0,0 +1,267 @@
+// Ollama backend
+// Talks to an Ollama server using `/api/chat`, and discovers the local models with `/api/tags`
+
+use crate::backend::{api_role, default_or_first, read_lines, Backend, Completion, Usage};
+use crate::conversation::Conversation;
+use async_trait::async_trait;
+use base64::Engine;
+use matrix_sdk::media::MediaFileHandle;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{error, info, warn};
+
+/// File extensions that are sent to the model as images
+const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "gif", "webp"];
+
+pub struct Ollama {
+    /// Base URL of the server, e.g. `http://localhost:11434`
+    base_url: String,
+    default_model: Option<String>,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<RequestMessage>,
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct RequestMessage {
+    role: &'static str,
+    content: String,
+    /// Base64 encoded images, for vision models
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatResponse {
+    message: ResponseMessage,
+    /// Tokens in the prompt, only set on the final response
+    prompt_eval_count: Option<u64>,
+    /// Tokens generated, only set on the final response
+    eval_count: Option<u64>,
+}
+
+impl ChatResponse {
+    /// The tokens used by the request, if this is the final response
+    fn usage(&self) -> Option<Usage> {
+        Some(Usage {
+            prompt_tokens: self.prompt_eval_count?,
+            completion_tokens: self.eval_count?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ResponseMessage {
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct TagList {
+    models: Vec<Tag>,
+}
+
+#[derive(Deserialize)]
+struct Tag {
+    name: String,
+}
+
+impl Ollama {
+    pub fn new(base_url: Option<String>, default_model: Option<String>) -> Self {
+        Ollama {
+            base_url: base_url
+                .unwrap_or("http://localhost:11434".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            default_model,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Ask the server which models have been pulled
+    async fn fetch_models(&self) -> Result<Vec<String>, String> {
+        let response = self
+            .client
+            .get(format!("{}/api/tags", self.base_url))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?;
+        let tags: TagList = response.json().await.map_err(|e| e.to_string())?;
+        Ok(tags.models.into_iter().map(|m| m.name).collect())
+    }
+
+    /// Send the conversation to the chat endpoint
+    async fn send_chat(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+        stream: bool,
+    ) -> Result<reqwest::Response, String> {
+        let model = match model {
+            Some(model) => model.clone(),
+            None => self.default_model().await?,
+        };
+        let request = ChatRequest {
+            model: &model,
+            messages: render(conversation),
+            stream,
+        };
+        info!(
+            "Sending request to {} with {} images",
+            self.base_url,
+            request
+                .messages
+                .iter()
+                .map(|message| message.images.len())
+                .sum::<usize>()
+        );
+
+        let response = self
+            .client
+            .post(format!("{}/api/chat", self.base_url))
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, body));
+        }
+        Ok(response)
+    }
+}
+
+/// Render the conversation as the list of messages the API takes
+/// Images are attached to the message they were sent with
+fn render(conversation: &Conversation) -> Vec<RequestMessage> {
+    let mut messages = Vec::new();
+    if let Some(system) = &conversation.system {
+        messages.push(RequestMessage {
+            role: "system",
+            content: system.clone(),
+            images: Vec::new(),
+        });
+    }
+    for example in &conversation.examples {
+        messages.push(RequestMessage {
+            role: api_role(example.role),
+            content: example.text.clone(),
+            images: Vec::new(),
+        });
+    }
+    if let Some(summary) = conversation.summary_prompt() {
+        messages.push(RequestMessage {
+            role: "system",
+            content: summary,
+            images: Vec::new(),
+        });
+    }
+    for message in &conversation.messages {
+        messages.push(RequestMessage {
+            role: api_role(message.role),
+            content: message.labelled_text(),
+            images: encode_images(&message.attachments),
+        });
+    }
+    messages
+}
+
+/// Read the media files that are images and base64 encode them
+fn encode_images(media: &[Arc<MediaFileHandle>]) -> Vec<String> {
+    let mut images = Vec::new();
+    for media_file in media {
+        let is_image = media_file
+            .path()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()));
+        if !is_image {
+            warn!("Skipping non-image file: {:?}", media_file.path());
+            continue;
+        }
+        match std::fs::read(media_file.path()) {
+            Ok(bytes) => images.push(base64::engine::general_purpose::STANDARD.encode(bytes)),
+            Err(e) => error!("Error reading {:?}: {}", media_file.path(), e),
+        }
+    }
+    images
+}
+
+#[async_trait]
+impl Backend for Ollama {
+    /// List the models pulled on the server
+    async fn list_models(&self) -> Result<Vec<String>, String> {
+        self.fetch_models().await
+    }
+
+    /// Use the configured default, or the first model available
+    async fn default_model(&self) -> Result<String, String> {
+        default_or_first(self, &self.default_model).await
+    }
+
+    async fn execute(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+    ) -> Result<Completion, String> {
+        let response = self.send_chat(model, conversation, false).await?;
+        let response: ChatResponse = response.json().await.map_err(|e| e.to_string())?;
+
+        if response.message.content.is_empty() {
+            return Err("Empty response from the model".to_string());
+        }
+        Ok(match response.usage() {
+            Some(usage) => Completion {
+                text: response.message.content,
+                usage,
+            },
+            None => Completion::estimated(conversation, response.message.content),
+        })
+    }
+
+    async fn execute_stream(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+        tx: UnboundedSender<String>,
+    ) -> Result<Completion, String> {
+        let mut response = self.send_chat(model, conversation, true).await?;
+
+        // The response is one JSON object per line
+        let mut text = String::new();
+        let mut usage = None;
+        read_lines(&mut response, |line| {
+            if line.iter().all(|c| c.is_ascii_whitespace()) {
+                return;
+            }
+            match serde_json::from_slice::<ChatResponse>(line) {
+                Ok(chunk) => {
+                    if let Some(chunk_usage) = chunk.usage() {
+                        usage = Some(chunk_usage);
+                    }
+                    if !chunk.message.content.is_empty() {
+                        text.push_str(&chunk.message.content);
+                        let _ = tx.send(chunk.message.content);
+                    }
+                }
+                Err(e) => warn!("Error parsing stream chunk: {}", e),
+            }
+        })
+        .await?;
+
+        if text.is_empty() {
+            return Err("Empty response from the model".to_string());
+        }
+        Ok(match usage {
+            Some(usage) => Completion { text, usage },
+            None => Completion::estimated(conversation, text),
+        })
+    }
+}
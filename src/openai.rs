@@ -0,0 +1,325 @@
+// OpenAI compatible backend
+// Talks directly to any server implementing `/v1/chat/completions`, e.g. OpenAI, vLLM or llama.cpp
+
+use crate::backend::{
+    api_role, default_or_first, find_model, read_lines, Backend, Completion, Usage,
+};
+use crate::conversation::Conversation;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, warn};
+
+pub struct OpenAI {
+    /// Base URL of the API, including the `/v1` suffix
+    base_url: String,
+    api_key: Option<String>,
+    /// Models to offer, if not set they are queried from the server
+    models: Option<Vec<String>>,
+    default_model: Option<String>,
+    /// Models the server reported the last time it was asked
+    served: Mutex<Option<Vec<String>>>,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<RequestMessage>,
+    stream: bool,
+    /// Only sent when streaming, to ask for the usage in the last chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+/// Tokens used by a request, as reported by the server
+#[derive(Debug, Deserialize)]
+struct ApiUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl From<ApiUsage> for Usage {
+    fn from(usage: ApiUsage) -> Self {
+        Usage {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RequestMessage {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    choices: Vec<Choice>,
+    usage: Option<ApiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Choice {
+    message: ResponseMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseMessage {
+    content: Option<String>,
+}
+
+/// A single server-sent event while streaming
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Only set on the last chunk, which has no choices
+    usage: Option<ApiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    delta: ResponseMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<Model>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Model {
+    id: String,
+}
+
+impl OpenAI {
+    pub fn new(
+        base_url: String,
+        api_key: Option<String>,
+        models: Option<Vec<String>>,
+        default_model: Option<String>,
+    ) -> Self {
+        OpenAI {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            models,
+            default_model,
+            served: Mutex::new(None),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Build a request to the given endpoint, with the API key if there is one
+    fn request(&self, method: reqwest::Method, endpoint: &str) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .request(method, format!("{}/{}", self.base_url, endpoint));
+        if let Some(api_key) = &self.api_key {
+            request.bearer_auth(api_key)
+        } else {
+            request
+        }
+    }
+
+    /// Ask the server which models it serves, and remember the answer
+    async fn fetch_models(&self) -> Result<Vec<String>, String> {
+        let response = self
+            .request(reqwest::Method::GET, "models")
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?;
+        let models: ModelList = response.json().await.map_err(|e| e.to_string())?;
+        let models: Vec<String> = models.data.into_iter().map(|m| m.id).collect();
+        *self.served.lock().unwrap() = Some(models.clone());
+        Ok(models)
+    }
+
+    /// Get the id the server uses for a model
+    /// Model names from aichat are `client:model`, e.g. `openai:gpt-4o`, so the client is
+    /// stripped unless the server has a model with the full name
+    /// The served models are only fetched once, `.list` refreshes them
+    async fn api_model(&self, model: &str) -> String {
+        let served = self.served.lock().unwrap().clone();
+        let served = match served {
+            Some(models) => Ok(models),
+            None => self.fetch_models().await,
+        };
+        if let Some(name) = served.ok().and_then(|models| find_model(model, &models)) {
+            return name;
+        }
+        match model.split_once(':') {
+            Some((_, bare)) => bare.to_string(),
+            None => model.to_string(),
+        }
+    }
+
+    /// Send the conversation to the chat completions endpoint
+    async fn send_chat(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+        stream: bool,
+    ) -> Result<reqwest::Response, String> {
+        let media = conversation.media();
+        if !media.is_empty() {
+            warn!("Ignoring {} media files, not supported yet", media.len());
+        }
+        let model = match model {
+            Some(model) => self.api_model(model).await,
+            None => self.default_model().await?,
+        };
+        let request = ChatRequest {
+            model: &model,
+            messages: render(conversation),
+            stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+        };
+        info!("Sending request to {}: {:?}", self.base_url, request);
+
+        let response = self
+            .request(reqwest::Method::POST, "chat/completions")
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, body));
+        }
+        Ok(response)
+    }
+}
+
+/// Render the conversation as the list of messages the API takes
+fn render(conversation: &Conversation) -> Vec<RequestMessage> {
+    let mut messages = Vec::new();
+    if let Some(system) = &conversation.system {
+        messages.push(RequestMessage {
+            role: "system",
+            content: system.clone(),
+        });
+    }
+    for example in &conversation.examples {
+        messages.push(RequestMessage {
+            role: api_role(example.role),
+            content: example.text.clone(),
+        });
+    }
+    if let Some(summary) = conversation.summary_prompt() {
+        messages.push(RequestMessage {
+            role: "system",
+            content: summary,
+        });
+    }
+    for message in &conversation.messages {
+        messages.push(RequestMessage {
+            role: api_role(message.role),
+            content: message.labelled_text(),
+        });
+    }
+    messages
+}
+
+#[async_trait]
+impl Backend for OpenAI {
+    /// List the configured models, or the ones the server reports
+    async fn list_models(&self) -> Result<Vec<String>, String> {
+        if let Some(models) = &self.models {
+            return Ok(models.clone());
+        }
+        self.fetch_models().await
+    }
+
+    /// Use the configured default, or the first model available
+    async fn default_model(&self) -> Result<String, String> {
+        default_or_first(self, &self.default_model).await
+    }
+
+    async fn execute(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+    ) -> Result<Completion, String> {
+        let response = self.send_chat(model, conversation, false).await?;
+        let response: ChatResponse = response.json().await.map_err(|e| e.to_string())?;
+
+        let text = response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .filter(|content| !content.is_empty())
+            .ok_or("Empty response from the model".to_string())?;
+        Ok(match response.usage {
+            Some(usage) => Completion {
+                text,
+                usage: usage.into(),
+            },
+            None => Completion::estimated(conversation, text),
+        })
+    }
+
+    async fn execute_stream(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+        tx: UnboundedSender<String>,
+    ) -> Result<Completion, String> {
+        let mut response = self.send_chat(model, conversation, true).await?;
+
+        // The response is a stream of `data: {...}` lines, ending with `data: [DONE]`
+        let mut text = String::new();
+        let mut usage = None;
+        read_lines(&mut response, |line| {
+            let line = String::from_utf8_lossy(line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                return;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                return;
+            }
+            match serde_json::from_str::<StreamChunk>(data) {
+                Ok(chunk) => {
+                    if chunk.usage.is_some() {
+                        usage = chunk.usage;
+                    }
+                    if let Some(delta) = chunk
+                        .choices
+                        .into_iter()
+                        .next()
+                        .and_then(|choice| choice.delta.content)
+                    {
+                        text.push_str(&delta);
+                        let _ = tx.send(delta);
+                    }
+                }
+                Err(e) => warn!("Error parsing stream chunk: {} - {}", e, data),
+            }
+        })
+        .await?;
+
+        if text.is_empty() {
+            return Err("Empty response from the model".to_string());
+        }
+        Ok(match usage {
+            Some(usage) => Completion {
+                text,
+                usage: usage.into(),
+            },
+            None => Completion::estimated(conversation, text),
+        })
+    }
+}
@@ -0,0 +1,89 @@
+// Pins
+// Messages pinned in the room are kept in the context, even if they were sent before a `.clear`.
+// Pinning uses the standard `m.room.pinned_events` state, so pins are shared with other clients.
+
+use crate::history;
+use matrix_sdk::{
+    deserialized_responses::RawSyncOrStrippedState,
+    ruma::{
+        events::{
+            room::pinned_events::RoomPinnedEventsEventContent, AnySyncTimelineEvent, SyncStateEvent,
+        },
+        serde::Raw,
+        MilliSecondsSinceUnixEpoch, OwnedEventId,
+    },
+    Room,
+};
+use tracing::error;
+
+/// Read the ids of the pinned events from the room state
+async fn pinned(room: &Room) -> Vec<OwnedEventId> {
+    match room
+        .get_state_event_static::<RoomPinnedEventsEventContent>()
+        .await
+    {
+        Ok(Some(RawSyncOrStrippedState::Sync(raw))) => match raw.deserialize() {
+            Ok(SyncStateEvent::Original(event)) => event.content.pinned,
+            Ok(SyncStateEvent::Redacted(_)) => Vec::new(),
+            Err(e) => {
+                error!("Error reading the pinned events: {}", e);
+                Vec::new()
+            }
+        },
+        Ok(_) => Vec::new(),
+        Err(e) => {
+            error!("Error reading the pinned events: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Pin events in the room, like all the pieces of a long answer
+pub async fn pin(room: &Room, event_ids: Vec<OwnedEventId>) -> Result<(), String> {
+    let mut pinned = pinned(room).await;
+    let count = pinned.len();
+    for event_id in event_ids {
+        if !pinned.contains(&event_id) {
+            pinned.push(event_id);
+        }
+    }
+    if pinned.len() == count {
+        return Ok(());
+    }
+    room.send_state_event(RoomPinnedEventsEventContent::new(pinned))
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Get the pinned events that aren't already in `events`, newest first
+/// The newest edit of each one is included too, so it's read with its current text
+pub async fn pinned_events(
+    room: &Room,
+    events: &[Raw<AnySyncTimelineEvent>],
+) -> Vec<Raw<AnySyncTimelineEvent>> {
+    let mut pinned_events = Vec::new();
+    for event_id in pinned(room).await {
+        let included = events.iter().any(|event| {
+            event.get_field::<OwnedEventId>("event_id").unwrap_or(None) == Some(event_id.clone())
+        });
+        if included {
+            continue;
+        }
+        let Some(event) = history::event(room, &event_id).await else {
+            continue;
+        };
+        pinned_events.extend(history::latest_edit(room, &event).await);
+        pinned_events.push(event);
+    }
+    // Pins are listed in the order they were pinned, not the order they were sent
+    // Edits are newer than the message they change, so they're read first
+    pinned_events.sort_by_key(|event| {
+        std::cmp::Reverse(
+            event
+                .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                .unwrap_or(None),
+        )
+    });
+    pinned_events
+}
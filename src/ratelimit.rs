@@ -0,0 +1,352 @@
+// Rate Limits
+// Messages are counted in sliding windows, per user or per room.
+// The counts are saved in the state directory, so restarting the bot doesn't reset anyone's quota.
+
+use crate::state::StateFile;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::error;
+
+/// A limit on the number of messages in a window of time
+#[derive(Debug, Deserialize, Clone)]
+pub struct RateLimit {
+    /// Maximum number of messages in the window
+    messages: u64,
+    /// Length of the window
+    window: Window,
+    /// Count the messages for each user or for each room
+    /// Defaults to user
+    per: Option<Scope>,
+}
+
+/// Length of a rate limit window
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Window {
+    Minute,
+    Hour,
+    Day,
+    Week,
+    /// 30 days
+    Month,
+}
+
+impl Window {
+    /// The length of the window in seconds
+    pub fn seconds(&self) -> u64 {
+        match self {
+            Window::Minute => 60,
+            Window::Hour => 60 * 60,
+            Window::Day => 24 * 60 * 60,
+            Window::Week => 7 * 24 * 60 * 60,
+            Window::Month => 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Window::Minute => write!(f, "minute"),
+            Window::Hour => write!(f, "hour"),
+            Window::Day => write!(f, "day"),
+            Window::Week => write!(f, "week"),
+            Window::Month => write!(f, "month"),
+        }
+    }
+}
+
+/// What a rate limit counts messages for
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    #[default]
+    User,
+    Room,
+}
+
+/// Why a message was rate limited
+pub enum Limited {
+    /// The user has used up their total message limit
+    Total { limit: u64 },
+    /// A windowed limit was hit, it resets after `resets_in` seconds
+    Window { limit: RateLimit, resets_in: u64 },
+}
+
+impl fmt::Display for Limited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limited::Total { limit } => {
+                write!(
+                    f,
+                    "you have used up your message limit of {} messages.",
+                    limit
+                )
+            }
+            Limited::Window { limit, resets_in } => {
+                let who = match limit.per.unwrap_or_default() {
+                    Scope::User => "you have",
+                    Scope::Room => "this room has",
+                };
+                write!(
+                    f,
+                    "{} used up the limit of {} messages per {}. It resets in {}.",
+                    who,
+                    limit.messages,
+                    limit.window,
+                    format_duration(*resets_in)
+                )
+            }
+        }
+    }
+}
+
+/// The counts that are saved between restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counts {
+    /// Total number of messages from each user
+    #[serde(default)]
+    totals: HashMap<String, u64>,
+    /// Times of the recent messages, in seconds since the epoch, keyed by user or room
+    #[serde(default)]
+    recent: HashMap<String, Vec<u64>>,
+}
+
+/// Keeps track of the messages sent by each user and in each room
+pub struct RateLimiter {
+    /// File the counts are saved to
+    file: StateFile,
+    counts: Counts,
+}
+
+impl RateLimiter {
+    /// Load the saved counts, starting fresh if there aren't any
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let (file, contents) = StateFile::open(path);
+        let counts = contents
+            .and_then(|contents| match serde_yaml::from_str(&contents) {
+                Ok(counts) => Some(counts),
+                Err(e) => {
+                    error!("Error reading the rate limits: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        RateLimiter { file, counts }
+    }
+
+    /// Save the counts, so they survive a restart
+    fn save(&self) {
+        match serde_yaml::to_string(&self.counts) {
+            Ok(contents) => self.file.save(contents),
+            Err(e) => error!("Error saving the rate limits: {}", e),
+        }
+    }
+
+    /// Total number of messages the user has sent
+    pub fn total(&self, user: &str) -> u64 {
+        self.counts.totals.get(user).copied().unwrap_or(0)
+    }
+
+    /// Describe how many messages are left in each limit, e.g. "15 of 20 messages per hour for you"
+    /// `now` is the current time in seconds since the epoch
+    pub fn remaining(&self, user: &str, room: &str, limits: &[RateLimit], now: u64) -> Vec<String> {
+        limits
+            .iter()
+            .map(|limit| {
+                let (key, whom) = match limit.per.unwrap_or_default() {
+                    Scope::User => (format!("user:{}", user), "you"),
+                    Scope::Room => (format!("room:{}", room), "this room"),
+                };
+                let window = limit.window.seconds();
+                let count = self
+                    .counts
+                    .recent
+                    .get(&key)
+                    .map(|times| times.iter().filter(|time| *time + window > now).count())
+                    .unwrap_or(0) as u64;
+                format!(
+                    "{} of {} messages per {} for {}",
+                    limit.messages.saturating_sub(count),
+                    limit.messages,
+                    limit.window,
+                    whom
+                )
+            })
+            .collect()
+    }
+
+    /// Check a message against the limits, and count it if it's allowed
+    /// `total_limit` is the limit on the number of messages a user can ever send,
+    /// and `now` is the current time in seconds since the epoch
+    pub fn check(
+        &mut self,
+        user: &str,
+        room: &str,
+        limits: &[RateLimit],
+        total_limit: Option<u64>,
+        now: u64,
+    ) -> Result<(), Limited> {
+        let user_key = format!("user:{}", user);
+        let room_key = format!("room:{}", room);
+
+        // Forget the messages that are older than every window
+        let longest = limits
+            .iter()
+            .map(|limit| limit.window.seconds())
+            .max()
+            .unwrap_or(0);
+        self.counts.recent.retain(|_, times| {
+            times.retain(|time| time + longest > now);
+            !times.is_empty()
+        });
+
+        if let Some(limit) = total_limit {
+            if self.total(user) >= limit {
+                return Err(Limited::Total { limit });
+            }
+        }
+        for limit in limits {
+            let key = match limit.per.unwrap_or_default() {
+                Scope::User => &user_key,
+                Scope::Room => &room_key,
+            };
+            let window = limit.window.seconds();
+            let times: Vec<u64> = self
+                .counts
+                .recent
+                .get(key)
+                .map(|times| {
+                    times
+                        .iter()
+                        .copied()
+                        .filter(|time| time + window > now)
+                        .collect()
+                })
+                .unwrap_or_default();
+            let count = times.len() as u64;
+            if count >= limit.messages {
+                // The quota resets once enough of the messages have left the window
+                let resets_in = times
+                    .get((count - limit.messages) as usize)
+                    .map(|time| time + window - now)
+                    .unwrap_or(window);
+                return Err(Limited::Window {
+                    limit: limit.clone(),
+                    resets_in,
+                });
+            }
+        }
+
+        *self.counts.totals.entry(user.to_string()).or_insert(0) += 1;
+        if longest > 0 {
+            for key in [user_key, room_key] {
+                self.counts.recent.entry(key).or_default().push(now);
+            }
+        }
+        self.save();
+        Ok(())
+    }
+}
+
+/// The current time in seconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Format a number of seconds as a rough duration, e.g. "3 hours"
+pub fn format_duration(seconds: u64) -> String {
+    let (count, unit) = match seconds {
+        0..=59 => (seconds, "second"),
+        60..=3599 => (seconds.div_ceil(60), "minute"),
+        3600..=86399 => (seconds.div_ceil(3600), "hour"),
+        _ => (seconds.div_ceil(86400), "day"),
+    };
+    if count == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", count, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(messages: u64, window: Window, per: Option<Scope>) -> RateLimit {
+        RateLimit {
+            messages,
+            window,
+            per,
+        }
+    }
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::load(None)
+    }
+
+    #[test]
+    fn resets_when_the_oldest_message_leaves_the_window() {
+        let mut limiter = limiter();
+        let limits = [limit(2, Window::Minute, None)];
+        assert!(limiter.check("@a:x", "!r:x", &limits, None, 1000).is_ok());
+        assert!(limiter.check("@a:x", "!r:x", &limits, None, 1020).is_ok());
+        match limiter.check("@a:x", "!r:x", &limits, None, 1030) {
+            Err(Limited::Window { resets_in, .. }) => assert_eq!(resets_in, 30),
+            _ => panic!("expected the window limit"),
+        }
+        // The first message has left the window, so there's room for one more
+        assert!(limiter.check("@a:x", "!r:x", &limits, None, 1060).is_ok());
+        match limiter.check("@a:x", "!r:x", &limits, None, 1061) {
+            Err(Limited::Window { resets_in, .. }) => assert_eq!(resets_in, 19),
+            _ => panic!("expected the window limit"),
+        }
+        // Rejected messages aren't counted
+        assert_eq!(limiter.total("@a:x"), 3);
+    }
+
+    #[test]
+    fn counts_users_separately() {
+        let mut limiter = limiter();
+        let limits = [limit(1, Window::Hour, None)];
+        assert!(limiter.check("@a:x", "!r:x", &limits, None, 0).is_ok());
+        assert!(limiter.check("@a:x", "!r:x", &limits, None, 1).is_err());
+        assert!(limiter.check("@b:x", "!r:x", &limits, None, 2).is_ok());
+    }
+
+    #[test]
+    fn room_limits_are_shared_by_everyone_in_the_room() {
+        let mut limiter = limiter();
+        let limits = [limit(2, Window::Hour, Some(Scope::Room))];
+        assert!(limiter.check("@a:x", "!r:x", &limits, None, 0).is_ok());
+        assert!(limiter.check("@b:x", "!r:x", &limits, None, 1).is_ok());
+        assert!(limiter.check("@c:x", "!r:x", &limits, None, 2).is_err());
+        assert!(limiter.check("@c:x", "!s:x", &limits, None, 3).is_ok());
+        assert_eq!(
+            limiter.remaining("@c:x", "!r:x", &limits, 4),
+            vec!["0 of 2 messages per hour for this room".to_string()]
+        );
+        assert_eq!(
+            limiter.remaining("@c:x", "!r:x", &limits, 3601),
+            vec!["2 of 2 messages per hour for this room".to_string()]
+        );
+    }
+
+    #[test]
+    fn total_limit_never_resets() {
+        let mut limiter = limiter();
+        assert!(limiter.check("@a:x", "!r:x", &[], Some(1), 0).is_ok());
+        assert!(matches!(
+            limiter.check("@a:x", "!r:x", &[], Some(1), Window::Month.seconds()),
+            Err(Limited::Total { limit: 1 })
+        ));
+    }
+}
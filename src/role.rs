@@ -3,6 +3,7 @@
 // Some models, especially the chat models, take a specific system prompt, and others you can just inject it as the first message.
 // Prompting the models with an example message can also be useful.
 
+use crate::conversation::{ChatMessage, Conversation};
 use serde::de::{self, Deserializer, Unexpected, Visitor};
 use serde::Deserialize;
 use std::fmt;
@@ -29,9 +30,11 @@ struct Message {
 /// The role of a single message in a conversation
 /// Can be parsed as either upper or lower case
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum MessageRole {
+pub enum MessageRole {
     User,
     Assistant,
+    /// Notices from the homeserver, these can't be used in examples
+    Server,
 }
 
 impl fmt::Display for MessageRole {
@@ -39,6 +42,7 @@ impl fmt::Display for MessageRole {
         match self {
             MessageRole::User => write!(f, "USER"),
             MessageRole::Assistant => write!(f, "ASSISTANT"),
+            MessageRole::Server => write!(f, "SERVER"),
         }
     }
 }
@@ -123,33 +127,60 @@ fn get_role(
     None
 }
 
-/// Prepends the role prompt to the message
+/// Check if a role with the given name is defined
+pub fn role_exists(
+    role: &str,
+    role_list: Option<Vec<RoleDetails>>,
+    default_roles: Option<Vec<RoleDetails>>,
+) -> bool {
+    get_role(Some(role.to_string()), role_list, default_roles).is_some()
+}
+
+/// List the names and descriptions of all the defined roles
+/// Roles in the role list hide inbuilt roles with the same name
+pub fn list_roles(
+    role_list: Option<Vec<RoleDetails>>,
+    default_roles: Option<Vec<RoleDetails>>,
+) -> Vec<(String, Option<String>)> {
+    let mut roles: Vec<(String, Option<String>)> = Vec::new();
+    for details in role_list
+        .unwrap_or_default()
+        .into_iter()
+        .chain(default_roles.unwrap_or_default())
+    {
+        if !roles.iter().any(|(name, _)| *name == details.name) {
+            roles.push((details.name, details.description));
+        }
+    }
+    roles
+}
+
+/// Prepends the role prompt and examples to the conversation
 pub fn prepend_role(
-    message: String,
+    conversation: &mut Conversation,
     role: Option<String>,
     role_list: Option<Vec<RoleDetails>>,
     default_roles: Option<Vec<RoleDetails>>,
-) -> String {
+) {
     if let Some(role_details) = get_role(role, role_list, default_roles) {
-        return prepend_role_internal(message, &role_details);
+        prepend_role_internal(conversation, &role_details);
     }
     // Nothing found, so just return
     // TODO: Provide an error message that it wasn't found
-    message
 }
 
-/// Prepends the role prompt to the message
-fn prepend_role_internal(message: String, role_details: &RoleDetails) -> String {
-    let mut role_prompt = role_details.prompt.clone().unwrap_or("".to_string());
-    if !role_prompt.is_empty() {
-        role_prompt.push('\n');
-    }
+/// Prepends the role prompt and examples to the conversation
+fn prepend_role_internal(conversation: &mut Conversation, role_details: &RoleDetails) {
+    conversation.system = role_details
+        .prompt
+        .clone()
+        .filter(|prompt| !prompt.is_empty());
     // Add the conversation example if it exists
-    if let Some(example) = role_details.example.clone() {
-        for message in example {
-            role_prompt.push_str(&format!("{}: {}\n", message.user, message.message));
-        }
-    }
-    role_prompt.push_str(&message);
-    role_prompt
+    conversation.examples = role_details
+        .example
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|message| ChatMessage::new(message.user, message.message))
+        .collect();
 }
@@ -0,0 +1,82 @@
+// Room Settings
+// The settings for a room are stored in a custom state event, so they survive `.clear`,
+// are visible to other clients, and can be edited by the room admins.
+// The bot may not have permission to send state events, so the settings can still be
+// picked up from the commands in the conversation, and the state event takes priority.
+
+use matrix_sdk::{
+    deserialized_responses::RawSyncOrStrippedState,
+    ruma::events::{macros::EventContent, SyncStateEvent},
+    Room,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// Settings for a room
+#[derive(Clone, Debug, Default, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "dev.chaz.settings", kind = State, state_key_type = EmptyStateKey)]
+pub struct RoomSettingsEventContent {
+    /// Model selected with `.model`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Whether `.lurk` is on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lurk: Option<bool>,
+    /// Whether `.mention` is on, so only messages that mention the bot are answered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mention: Option<bool>,
+    /// Role selected with `.role`, overrides the role in the config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    /// System prompt set with `.system`, replaces the role until it's reset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+}
+
+pub type RoomSettings = RoomSettingsEventContent;
+
+impl RoomSettings {
+    /// Fill in any settings that aren't set from `other`
+    pub fn or(self, other: RoomSettings) -> RoomSettings {
+        RoomSettings {
+            model: self.model.or(other.model),
+            lurk: self.lurk.or(other.lurk),
+            mention: self.mention.or(other.mention),
+            role: self.role.or(other.role),
+            system: self.system.or(other.system),
+        }
+    }
+}
+
+/// Read the settings from the room state
+/// Returns None if they have never been saved
+pub async fn load(room: &Room) -> Option<RoomSettings> {
+    match room
+        .get_state_event_static::<RoomSettingsEventContent>()
+        .await
+    {
+        Ok(Some(RawSyncOrStrippedState::Sync(raw))) => match raw.deserialize() {
+            Ok(SyncStateEvent::Original(event)) => Some(event.content),
+            Ok(SyncStateEvent::Redacted(_)) => None,
+            Err(e) => {
+                error!("Error reading the room settings: {}", e);
+                None
+            }
+        },
+        Ok(_) => None,
+        Err(e) => {
+            error!("Error reading the room settings: {}", e);
+            None
+        }
+    }
+}
+
+/// Change the settings saved in the room state
+pub async fn update(room: &Room, change: impl FnOnce(&mut RoomSettings)) -> Result<(), String> {
+    let mut settings = load(room).await.unwrap_or_default();
+    change(&mut settings);
+    room.send_state_event(settings)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
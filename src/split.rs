@@ -0,0 +1,289 @@
+// Splitting
+// Homeservers reject events over 64 KiB, so long replies are split into several messages.
+// The pieces are sent as a chain of replies, and joined back into one answer when the context is read.
+
+use matrix_sdk::{
+    ruma::{
+        events::{
+            relation::{InReplyTo, Thread},
+            room::message::{Relation, RoomMessageEventContent},
+        },
+        OwnedEventId,
+    },
+    Room,
+};
+
+/// Largest piece of a reply sent in one event, in bytes
+/// A piece is edited while it streams, and an edit carries the text four times:
+/// the plain body and the HTML, for the fallback and again for the new content
+const MAX_CHUNK_BYTES: usize = 12 * 1024;
+
+/// Largest event the homeserver accepts, in bytes
+const MAX_EVENT_BYTES: usize = 64 * 1024;
+
+/// Room left in an event for the relation, the sender and the other fields
+const EVENT_OVERHEAD_BYTES: usize = 2 * 1024;
+
+/// Split the text into pieces that each fit in an event
+/// Splits between paragraphs where possible, and never inside a code block without closing it
+pub fn split_message(text: &str) -> Vec<String> {
+    // Markup and escaping can make the HTML much longer than the text, so check the real size
+    let mut limit = MAX_CHUNK_BYTES;
+    loop {
+        let chunks = split_with_limit(text, limit);
+        if limit <= 1024 || chunks.iter().all(|chunk| fits_in_edit(chunk)) {
+            return chunks;
+        }
+        limit /= 2;
+    }
+}
+
+/// Check that an edit replacing a message with the text is small enough to send
+fn fits_in_edit(text: &str) -> bool {
+    // An edit holds the content twice, once as the fallback and once as the new content
+    serde_json::to_vec(&RoomMessageEventContent::text_markdown(text))
+        .is_ok_and(|json| 2 * json.len() + EVENT_OVERHEAD_BYTES <= MAX_EVENT_BYTES)
+}
+
+/// Split the text into pieces of at most `limit` bytes
+fn split_with_limit(text: &str, limit: usize) -> Vec<String> {
+    if text.len() <= limit {
+        return vec![text.to_string()];
+    }
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    for block in blocks(text) {
+        for piece in split_block(&block, limit) {
+            if !chunk.is_empty() && chunk.len() + 2 + piece.len() > limit {
+                chunks.push(std::mem::take(&mut chunk));
+            }
+            if !chunk.is_empty() {
+                chunk.push_str("\n\n");
+            }
+            chunk.push_str(&piece);
+        }
+    }
+    if !chunk.is_empty() || chunks.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Split the text into paragraphs, keeping code blocks whole
+/// A code block is always a block of its own, even without blank lines around it
+fn blocks(text: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut block: Vec<&str> = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        let fence = line.trim_start().starts_with("```");
+        if !in_code && (fence || line.trim().is_empty()) {
+            if !block.is_empty() {
+                blocks.push(block.join("\n"));
+                block.clear();
+            }
+            if !fence {
+                continue;
+            }
+        }
+        block.push(line);
+        if fence {
+            in_code = !in_code;
+            if !in_code {
+                blocks.push(block.join("\n"));
+                block.clear();
+            }
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(block.join("\n"));
+    }
+    blocks
+}
+
+/// Split a single paragraph or code block that is too long to send, at line boundaries
+/// Code blocks are closed at the end of each piece and reopened at the start of the next
+fn split_block(block: &str, limit: usize) -> Vec<String> {
+    if block.len() <= limit {
+        return vec![block.to_string()];
+    }
+    let mut lines: Vec<&str> = block.lines().collect();
+    // The opening line of the fence includes the language, so it's repeated on every piece
+    let fence = lines
+        .first()
+        .filter(|line| line.trim_start().starts_with("```"))
+        .map(|line| line.to_string());
+    if fence.is_some() {
+        lines.remove(0);
+        if lines
+            .last()
+            .is_some_and(|line| line.trim_start().starts_with("```"))
+        {
+            lines.pop();
+        }
+    }
+    let overhead = fence.as_ref().map(|fence| fence.len() + 5).unwrap_or(0);
+    let limit = limit.saturating_sub(overhead).max(64);
+
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    for line in lines {
+        for part in split_line(line, limit) {
+            if !piece.is_empty() && piece.len() + 1 + part.len() > limit {
+                pieces.push(std::mem::take(&mut piece));
+            }
+            if !piece.is_empty() {
+                piece.push('\n');
+            }
+            piece.push_str(part);
+        }
+    }
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+    match fence {
+        Some(fence) => pieces
+            .into_iter()
+            .map(|piece| format!("{}\n{}\n```", fence, piece))
+            .collect(),
+        None => pieces,
+    }
+}
+
+/// Split a line that is longer than the limit, without splitting any characters
+fn split_line(line: &str, limit: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut rest = line;
+    while rest.len() > limit {
+        let mut end = limit;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (part, remainder) = rest.split_at(end);
+        parts.push(part);
+        rest = remainder;
+    }
+    parts.push(rest);
+    parts
+}
+
+/// Send the pieces of a reply that come after the first, each replying to the piece before it
+/// `previous` is the first piece, and `thread` keeps the pieces in the same thread
+pub async fn send_chain(
+    room: &Room,
+    previous: OwnedEventId,
+    thread: &Option<OwnedEventId>,
+    chunks: &[String],
+) -> Result<(), String> {
+    let mut previous = previous;
+    for chunk in chunks {
+        let mut content = RoomMessageEventContent::text_markdown(chunk);
+        content.relates_to = Some(match thread {
+            Some(thread) => Relation::Thread(Thread::reply(thread.clone(), previous.clone())),
+            None => Relation::Reply {
+                in_reply_to: InReplyTo::new(previous.clone()),
+            },
+        });
+        let response = room.send(content).await.map_err(|e| e.to_string())?;
+        previous = response.event_id;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(split_message("hello"), vec!["hello".to_string()]);
+    }
+
+    #[test]
+    fn splits_between_paragraphs() {
+        let paragraph = "word ".repeat(1000);
+        let text = vec![paragraph.trim(); 10].join("\n\n");
+        let chunks = split_message(&text);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.len() <= MAX_CHUNK_BYTES);
+            assert!(!chunk.starts_with('\n') && !chunk.ends_with('\n'));
+        }
+        assert_eq!(chunks.join("\n\n"), text);
+    }
+
+    #[test]
+    fn reopens_code_blocks() {
+        let code = (0..2000)
+            .map(|i| format!("let x{} = {};", i, i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = format!("```rust\n{}\n```", code);
+        let chunks = split_message(&text);
+        assert!(chunks.len() > 1);
+        let mut lines = Vec::new();
+        for chunk in &chunks {
+            assert!(chunk.len() <= MAX_CHUNK_BYTES);
+            assert!(chunk.starts_with("```rust\n"));
+            assert!(chunk.ends_with("\n```"));
+            lines.extend(
+                chunk
+                    .trim_start_matches("```rust\n")
+                    .trim_end_matches("\n```")
+                    .lines()
+                    .map(|line| line.to_string()),
+            );
+        }
+        assert_eq!(lines.join("\n"), code);
+    }
+
+    #[test]
+    fn reopens_code_blocks_after_text() {
+        let code = (0..2000)
+            .map(|i| format!("let x{} = {};", i, i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = format!("Here is the code:\n```rust\n{}\n```\nThat's all.", code);
+        let chunks = split_message(&text);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.len() <= MAX_CHUNK_BYTES);
+            // Every piece opens and closes its own fences
+            let fences = chunk
+                .lines()
+                .filter(|line| line.trim_start().starts_with("```"))
+                .count();
+            assert_eq!(fences % 2, 0, "unbalanced fences in {:?}", chunk);
+        }
+        assert!(chunks[0].starts_with("Here is the code:"));
+        assert!(chunks.last().unwrap().ends_with("\n```\n\nThat's all."));
+    }
+
+    #[test]
+    fn splits_long_lines_at_char_boundaries() {
+        let text = "é".repeat(MAX_CHUNK_BYTES);
+        let chunks = split_message(&text);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.len() <= MAX_CHUNK_BYTES);
+        }
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn split_line_keeps_characters_whole() {
+        let parts = split_line("aé", 2);
+        assert_eq!(parts, vec!["a", "é"]);
+    }
+
+    #[test]
+    fn escaped_text_fits_in_an_edit() {
+        // Every `<` becomes `&lt;` in the HTML, so the pieces have to be smaller
+        let text = "<".repeat(3 * MAX_CHUNK_BYTES);
+        let chunks = split_message(&text);
+        for chunk in &chunks {
+            assert!(fits_in_edit(chunk));
+        }
+        assert_eq!(chunks.concat(), text);
+    }
+}
@@ -0,0 +1,58 @@
+// State files
+// Counts that need to survive a restart are saved as files in the state directory.
+// Writes happen on a background task, and go to a temporary file that is renamed over the old one,
+// so a crash never leaves a half written file behind.
+
+use std::{fs, io, path::Path, path::PathBuf};
+use tokio::sync::watch;
+use tracing::error;
+
+/// A file in the state directory that is rewritten whenever its contents change
+pub struct StateFile {
+    /// Sends the newest contents to the writer task
+    tx: Option<watch::Sender<String>>,
+}
+
+impl StateFile {
+    /// Read the file, and start the task that writes it
+    /// Nothing is saved if there is no path
+    pub fn open(path: Option<PathBuf>) -> (Self, Option<String>) {
+        let Some(path) = path else {
+            return (StateFile { tx: None }, None);
+        };
+        let contents = fs::read_to_string(&path).ok();
+        let (tx, mut rx) = watch::channel(String::new());
+        tokio::spawn(async move {
+            // Only the newest contents are kept, so a burst of changes is written once
+            while rx.changed().await.is_ok() {
+                let contents = rx.borrow_and_update().clone();
+                let path = path.clone();
+                let result = tokio::task::spawn_blocking(move || write(&path, &contents)).await;
+                match result {
+                    Ok(Err(e)) => error!("Error saving the state file: {}", e),
+                    Err(e) => error!("Error saving the state file: {}", e),
+                    Ok(Ok(())) => {}
+                }
+            }
+        });
+        (StateFile { tx: Some(tx) }, contents)
+    }
+
+    /// Save new contents, the file is written in the background
+    pub fn save(&self, contents: String) {
+        if let Some(tx) = &self.tx {
+            tx.send_replace(contents);
+        }
+    }
+}
+
+/// Write the file by renaming a temporary file over it
+fn write(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)
+}
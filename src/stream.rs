@@ -0,0 +1,142 @@
+// Streaming
+// Replies are posted as a placeholder as soon as generation starts,
+// and then edited in place as the backend sends more of the response.
+
+use crate::backend::{Backend, Completion};
+use crate::conversation::Conversation;
+use crate::split;
+use matrix_sdk::{
+    ruma::{
+        events::room::message::{
+            AddMentions, ForwardThread, OriginalRoomMessageEvent, ReplacementMetadata,
+            RoomMessageEventContent,
+        },
+        OwnedEventId,
+    },
+    Room,
+};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tracing::error;
+
+/// Posted as the reply until the first tokens arrive
+const PLACEHOLDER: &str = "…";
+
+/// How often a streamed reply is edited
+#[derive(Debug, Deserialize, Clone)]
+pub struct StreamingConfig {
+    /// Edit the reply after this many tokens
+    /// Defaults to 20
+    tokens: Option<usize>,
+    /// Edit the reply after this many milliseconds
+    /// Defaults to 1000
+    interval_ms: Option<u64>,
+}
+
+/// A reply that is being edited as the response comes in
+struct StreamingReply {
+    room: Room,
+    event_id: OwnedEventId,
+    /// The text the reply currently shows
+    text: String,
+}
+
+impl StreamingReply {
+    /// Post the placeholder reply
+    async fn start(room: &Room, reply_to: &OriginalRoomMessageEvent) -> Result<Self, String> {
+        let content = RoomMessageEventContent::text_plain(PLACEHOLDER).make_reply_to(
+            reply_to,
+            ForwardThread::Yes,
+            AddMentions::No,
+        );
+        let response = room.send(content).await.map_err(|e| e.to_string())?;
+        Ok(StreamingReply {
+            room: room.clone(),
+            event_id: response.event_id,
+            text: PLACEHOLDER.to_string(),
+        })
+    }
+
+    /// Replace the text of the reply, if it has changed
+    /// Only the first piece of a long response fits, the rest is sent once it's finished
+    async fn update(&mut self, text: &str) {
+        let chunks = split::split_message(text);
+        let text = chunks[0].as_str();
+        if text.is_empty() || text == self.text {
+            return;
+        }
+        let content = RoomMessageEventContent::text_markdown(text)
+            .make_replacement(ReplacementMetadata::new(self.event_id.clone(), None), None);
+        match self.room.send(content).await {
+            Ok(_) => self.text = text.to_string(),
+            Err(e) => error!("Error editing the reply: {}", e),
+        }
+    }
+
+    /// Remove the reply, used if generation fails
+    async fn remove(&self) {
+        if let Err(e) = self.room.redact(&self.event_id, None, None).await {
+            error!("Error removing the reply: {}", e);
+        }
+    }
+
+    /// Receive pieces of the response and edit the reply as they arrive
+    /// Returns once the sender is dropped
+    async fn follow(&mut self, mut rx: UnboundedReceiver<String>, config: &StreamingConfig) {
+        let tokens = config.tokens.unwrap_or(20);
+        let interval = Duration::from_millis(config.interval_ms.unwrap_or(1000));
+
+        let mut text = String::new();
+        let mut pending = 0;
+        let mut last_edit = Instant::now();
+        while let Some(piece) = rx.recv().await {
+            text.push_str(&piece);
+            pending += 1;
+            if pending >= tokens || last_edit.elapsed() >= interval {
+                self.update(&text).await;
+                pending = 0;
+                last_edit = Instant::now();
+            }
+        }
+        self.update(&text).await;
+    }
+}
+
+/// Run the conversation and stream the response into a reply to `reply_to`
+/// Returns the full response
+pub async fn respond(
+    room: &Room,
+    reply_to: &OriginalRoomMessageEvent,
+    backend: &dyn Backend,
+    model: &Option<String>,
+    conversation: &Conversation,
+    config: &StreamingConfig,
+) -> Result<Completion, String> {
+    let mut reply = StreamingReply::start(room, reply_to).await?;
+
+    let (tx, rx) = unbounded_channel();
+    let (result, _) = tokio::join!(
+        backend.execute_stream(model, conversation, tx),
+        reply.follow(rx, config)
+    );
+
+    match result {
+        Ok(response) => {
+            // Make sure the final edit has the full response, long responses continue in more messages
+            let chunks = split::split_message(&response.text);
+            reply.update(&chunks[0]).await;
+            let thread = crate::thread_root(&reply_to.content);
+            if let Err(e) =
+                split::send_chain(room, reply.event_id.clone(), &thread, &chunks[1..]).await
+            {
+                error!("Error sending the rest of the reply: {}", e);
+            }
+            Ok(response)
+        }
+        Err(e) => {
+            reply.remove().await;
+            Err(e)
+        }
+    }
+}
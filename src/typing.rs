@@ -0,0 +1,32 @@
+// Typing
+// While a response is being generated the bot shows as typing in the room.
+// Typing notices expire on their own, so they are refreshed until the response is done.
+
+use matrix_sdk::Room;
+use std::future::Future;
+use std::time::Duration;
+use tracing::error;
+
+/// How often the typing notice is refreshed
+/// The SDK sends notices that time out after 4 seconds
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+
+/// Show the bot as typing in the room until `future` finishes, and return its output
+pub async fn while_typing<F: Future>(room: &Room, future: F) -> F::Output {
+    let typing = async {
+        loop {
+            if let Err(e) = room.typing_notice(true).await {
+                error!("Error sending typing notice: {}", e);
+            }
+            tokio::time::sleep(TYPING_REFRESH).await;
+        }
+    };
+    let output = tokio::select! {
+        output = future => output,
+        _ = typing => unreachable!("the typing notice is sent forever"),
+    };
+    if let Err(e) = room.typing_notice(false).await {
+        error!("Error stopping typing notice: {}", e);
+    }
+    output
+}
@@ -0,0 +1,584 @@
+// Usage
+// Every request to the backend is recorded in a ledger, with the tokens it used and what they cost.
+// Budgets are checked against the ledger before a request is sent, so a long paste can't blow through them.
+// The ledger is saved in the state directory next to the rate limits.
+
+use crate::backend::{Backend, Completion, Usage};
+use crate::conversation::{estimate_tokens, Conversation};
+use crate::model::{self, ModelDetails};
+use crate::ratelimit::{format_duration, now, Scope, Window};
+use crate::state::StateFile;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{error, info};
+
+/// A cap on the tokens or cost of the requests in a window of time
+#[derive(Debug, Deserialize, Clone)]
+pub struct UsageLimit {
+    /// Maximum number of prompt and completion tokens
+    tokens: Option<u64>,
+    /// Maximum cost, in the same currency as the model prices
+    cost: Option<f64>,
+    /// Length of the window
+    /// Defaults to no window, so the limit is on the total
+    window: Option<Window>,
+    /// Count the usage for each user or for each room
+    /// Defaults to user
+    per: Option<Scope>,
+    /// Only count requests to this model
+    /// Defaults to counting every model
+    model: Option<String>,
+}
+
+impl UsageLimit {
+    /// Whether requests to the model count towards this limit
+    fn applies_to(&self, model: &str) -> bool {
+        self.model.as_ref().is_none_or(|limit| limit == model)
+    }
+}
+
+/// The tokens and cost of a set of requests
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Tally {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+impl Tally {
+    pub fn tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    fn add(&mut self, usage: &Usage, cost: f64) {
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.cost += cost;
+    }
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requests, {} tokens ({} prompt, {} completion), cost {:.2}",
+            self.requests,
+            self.tokens(),
+            self.prompt_tokens,
+            self.completion_tokens,
+            self.cost
+        )
+    }
+}
+
+/// Why a request was over budget
+pub struct OverBudget {
+    limit: UsageLimit,
+    /// Seconds until there is enough budget for the request, if it can ever fit
+    resets_in: Option<u64>,
+}
+
+impl fmt::Display for OverBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let whose = match self.limit.per.unwrap_or_default() {
+            Scope::User => "your",
+            Scope::Room => "this room's",
+        };
+        let mut amounts = Vec::new();
+        if let Some(tokens) = self.limit.tokens {
+            amounts.push(format!("{} tokens", tokens));
+        }
+        if let Some(cost) = self.limit.cost {
+            amounts.push(format!("{:.2}", cost));
+        }
+        write!(
+            f,
+            "this message would go over {} budget of {}",
+            whose,
+            amounts.join(" or ")
+        )?;
+        if let Some(model) = &self.limit.model {
+            write!(f, " on {}", model)?;
+        }
+        if let Some(window) = self.limit.window {
+            write!(f, " per {}", window)?;
+        }
+        write!(f, ".")?;
+        if let Some(resets_in) = self.resets_in {
+            write!(f, " It resets in {}.", format_duration(resets_in))?;
+        }
+        Ok(())
+    }
+}
+
+/// A single request, kept while it is inside a budget window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    /// Seconds since the epoch
+    time: u64,
+    user: String,
+    room: String,
+    model: String,
+    tokens: u64,
+    cost: f64,
+}
+
+/// The usage that is saved between restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Ledger {
+    /// Total usage for each model, keyed by user or room
+    #[serde(default)]
+    totals: HashMap<String, HashMap<String, Tally>>,
+    /// The recent requests, oldest first
+    #[serde(default)]
+    recent: Vec<Record>,
+}
+
+/// Keeps track of the tokens used by each user and in each room
+pub struct UsageLedger {
+    /// File the ledger is saved to
+    file: StateFile,
+    ledger: Ledger,
+    /// The current time in seconds since the epoch, replaced in tests
+    clock: fn() -> u64,
+}
+
+impl UsageLedger {
+    /// Load the saved ledger, starting fresh if there isn't one
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let (file, contents) = StateFile::open(path);
+        let ledger = contents
+            .and_then(|contents| match serde_yaml::from_str(&contents) {
+                Ok(ledger) => Some(ledger),
+                Err(e) => {
+                    error!("Error reading the usage ledger: {}", e);
+                    None
+                }
+            })
+            .unwrap_or_default();
+        UsageLedger {
+            file,
+            ledger,
+            clock: now,
+        }
+    }
+
+    /// Save the ledger, so it survives a restart
+    fn save(&self) {
+        match serde_yaml::to_string(&self.ledger) {
+            Ok(contents) => self.file.save(contents),
+            Err(e) => error!("Error saving the usage ledger: {}", e),
+        }
+    }
+
+    /// Total usage of the user for each model
+    pub fn user_totals(&self, user: &str) -> HashMap<String, Tally> {
+        self.ledger
+            .totals
+            .get(&format!("user:{}", user))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Total usage of every user for each model, sorted by user
+    pub fn all_user_totals(&self) -> Vec<(String, HashMap<String, Tally>)> {
+        let mut totals: Vec<_> = self
+            .ledger
+            .totals
+            .iter()
+            .filter_map(|(key, models)| {
+                let user = key.strip_prefix("user:")?;
+                Some((user.to_string(), models.clone()))
+            })
+            .collect();
+        totals.sort_by(|a, b| a.0.cmp(&b.0));
+        totals
+    }
+
+    /// Describe how much of each budget is left, e.g. "80000 of 100000 tokens per day for you"
+    pub fn remaining(&self, user: &str, room: &str, limits: &[UsageLimit]) -> Vec<String> {
+        limits
+            .iter()
+            .map(|limit| {
+                let (tokens, cost, _) = self.used(limit, user, room);
+                let mut amounts = Vec::new();
+                if let Some(max) = limit.tokens {
+                    amounts.push(format!("{} of {} tokens", max.saturating_sub(tokens), max));
+                }
+                if let Some(max) = limit.cost {
+                    amounts.push(format!("{:.2} of {:.2}", (max - cost).max(0.0), max));
+                }
+                let mut line = amounts.join(" and ");
+                if let Some(model) = &limit.model {
+                    line.push_str(&format!(" on {}", model));
+                }
+                if let Some(window) = limit.window {
+                    line.push_str(&format!(" per {}", window));
+                }
+                line.push_str(match limit.per.unwrap_or_default() {
+                    Scope::User => " for you",
+                    Scope::Room => " for this room",
+                });
+                line
+            })
+            .collect()
+    }
+
+    /// The tokens and cost counted against the limit so far
+    /// Also returns the recent requests that were counted, so we know when they leave the window
+    fn used(&self, limit: &UsageLimit, user: &str, room: &str) -> (u64, f64, Vec<&Record>) {
+        let scope = limit.per.unwrap_or_default();
+        let Some(window) = limit.window else {
+            let key = match scope {
+                Scope::User => format!("user:{}", user),
+                Scope::Room => format!("room:{}", room),
+            };
+            let (tokens, cost) = self
+                .ledger
+                .totals
+                .get(&key)
+                .into_iter()
+                .flatten()
+                .filter(|(model, _)| limit.applies_to(model))
+                .fold((0, 0.0), |(tokens, cost), (_, tally)| {
+                    (tokens + tally.tokens(), cost + tally.cost)
+                });
+            return (tokens, cost, Vec::new());
+        };
+        let start = (self.clock)().saturating_sub(window.seconds());
+        let records: Vec<&Record> = self
+            .ledger
+            .recent
+            .iter()
+            .filter(|record| record.time > start && limit.applies_to(&record.model))
+            .filter(|record| match scope {
+                Scope::User => record.user == user,
+                Scope::Room => record.room == room,
+            })
+            .collect();
+        let tokens = records.iter().map(|record| record.tokens).sum();
+        let cost = records.iter().map(|record| record.cost).sum();
+        (tokens, cost, records)
+    }
+
+    /// Check that a request fits in every budget
+    /// `prompt_tokens` and `prompt_cost` are the estimate for the prompt, the completion isn't known yet
+    pub fn check(
+        &self,
+        user: &str,
+        room: &str,
+        model: &str,
+        prompt_tokens: u64,
+        prompt_cost: f64,
+        limits: &[UsageLimit],
+    ) -> Result<(), OverBudget> {
+        for limit in limits.iter().filter(|limit| limit.applies_to(model)) {
+            let (tokens, cost, records) = self.used(limit, user, room);
+            let over = |tokens: u64, cost: f64| {
+                limit.tokens.is_some_and(|max| tokens + prompt_tokens > max)
+                    || limit.cost.is_some_and(|max| cost + prompt_cost > max)
+            };
+            if !over(tokens, cost) {
+                continue;
+            }
+            // The budget frees up as the oldest requests leave the window
+            let resets_in = limit.window.and_then(|window| {
+                if over(0, 0.0) {
+                    return None;
+                }
+                let (mut tokens, mut cost) = (tokens, cost);
+                for record in records {
+                    tokens -= record.tokens;
+                    cost -= record.cost;
+                    if !over(tokens, cost) {
+                        return Some(
+                            (record.time + window.seconds()).saturating_sub((self.clock)()),
+                        );
+                    }
+                }
+                None
+            });
+            return Err(OverBudget {
+                limit: limit.clone(),
+                resets_in,
+            });
+        }
+        Ok(())
+    }
+
+    /// Add a request to the ledger
+    /// `keep` is how long the request is needed for the budget windows, in seconds
+    pub fn record(
+        &mut self,
+        user: &str,
+        room: &str,
+        model: &str,
+        usage: &Usage,
+        cost: f64,
+        keep: u64,
+    ) {
+        for key in [format!("user:{}", user), format!("room:{}", room)] {
+            self.ledger
+                .totals
+                .entry(key)
+                .or_default()
+                .entry(model.to_string())
+                .or_default()
+                .add(usage, cost);
+        }
+        let now = (self.clock)();
+        self.ledger.recent.retain(|record| record.time + keep > now);
+        if keep > 0 {
+            self.ledger.recent.push(Record {
+                time: now,
+                user: user.to_string(),
+                room: room.to_string(),
+                model: model.to_string(),
+                tokens: usage.prompt_tokens + usage.completion_tokens,
+                cost,
+            });
+        }
+        self.save();
+    }
+}
+
+/// Wraps a backend to check the budgets of a user before each request, and record what it used
+pub struct Metered {
+    backend: Arc<dyn Backend>,
+    ledger: Arc<Mutex<UsageLedger>>,
+    user: String,
+    room: String,
+    limits: Vec<UsageLimit>,
+    models: Option<Vec<ModelDetails>>,
+}
+
+impl Metered {
+    pub fn new(
+        backend: Arc<dyn Backend>,
+        ledger: Arc<Mutex<UsageLedger>>,
+        user: String,
+        room: String,
+        limits: Vec<UsageLimit>,
+        models: Option<Vec<ModelDetails>>,
+    ) -> Self {
+        Metered {
+            backend,
+            ledger,
+            user,
+            room,
+            limits,
+            models,
+        }
+    }
+
+    /// Get the name of the model the request goes to, so it can be priced
+    async fn model_name(&self, model: &Option<String>) -> Result<String, String> {
+        match model {
+            Some(model) => Ok(model.clone()),
+            None => self.backend.default_model().await,
+        }
+    }
+
+    /// Check the budgets before the conversation is sent to the model
+    fn check(&self, model: &str, conversation: &Conversation) -> Result<(), String> {
+        let prompt_tokens = estimate_tokens(&conversation.full_transcript()) as u64;
+        let prompt_usage = Usage {
+            prompt_tokens,
+            completion_tokens: 0,
+        };
+        let prompt_cost = model::cost(model, &prompt_usage, &self.models);
+        self.ledger
+            .lock()
+            .unwrap()
+            .check(
+                &self.user,
+                &self.room,
+                model,
+                prompt_tokens,
+                prompt_cost,
+                &self.limits,
+            )
+            .map_err(|over| {
+                info!("User {} is over budget: {}", self.user, over);
+                over.to_string()
+            })
+    }
+
+    /// Add the completion to the ledger
+    fn record(&self, model: &str, completion: &Completion) {
+        let cost = model::cost(model, &completion.usage, &self.models);
+        let keep = self
+            .limits
+            .iter()
+            .filter_map(|limit| limit.window)
+            .map(|window| window.seconds())
+            .max()
+            .unwrap_or(0);
+        self.ledger.lock().unwrap().record(
+            &self.user,
+            &self.room,
+            model,
+            &completion.usage,
+            cost,
+            keep,
+        );
+    }
+}
+
+#[async_trait]
+impl Backend for Metered {
+    async fn execute(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+    ) -> Result<Completion, String> {
+        let name = self.model_name(model).await?;
+        self.check(&name, conversation)?;
+        let completion = self.backend.execute(model, conversation).await?;
+        self.record(&name, &completion);
+        Ok(completion)
+    }
+
+    async fn execute_stream(
+        &self,
+        model: &Option<String>,
+        conversation: &Conversation,
+        tx: UnboundedSender<String>,
+    ) -> Result<Completion, String> {
+        let name = self.model_name(model).await?;
+        self.check(&name, conversation)?;
+        let completion = self.backend.execute_stream(model, conversation, tx).await?;
+        self.record(&name, &completion);
+        Ok(completion)
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, String> {
+        self.backend.list_models().await
+    }
+
+    async fn default_model(&self) -> Result<String, String> {
+        self.backend.default_model().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// The time the ledger sees, each test runs on its own thread
+        static TIME: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn set_time(time: u64) {
+        TIME.with(|now| now.set(time));
+    }
+
+    fn ledger() -> UsageLedger {
+        let mut ledger = UsageLedger::load(None);
+        ledger.clock = || TIME.with(|now| now.get());
+        ledger
+    }
+
+    fn tokens(tokens: u64, window: Option<Window>, model: Option<&str>) -> UsageLimit {
+        UsageLimit {
+            tokens: Some(tokens),
+            cost: None,
+            window,
+            per: None,
+            model: model.map(|model| model.to_string()),
+        }
+    }
+
+    fn usage(tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens: tokens,
+            completion_tokens: 0,
+        }
+    }
+
+    #[test]
+    fn windowed_budgets_reset_but_totals_dont() {
+        let mut ledger = ledger();
+        let windowed = [tokens(100, Some(Window::Hour), None)];
+        let total = [tokens(100, None, None)];
+        set_time(1000);
+        ledger.record("@a:x", "!r:x", "m", &usage(60), 0.0, Window::Hour.seconds());
+        assert!(ledger
+            .check("@a:x", "!r:x", "m", 50, 0.0, &windowed)
+            .is_err());
+        assert!(ledger.check("@a:x", "!r:x", "m", 50, 0.0, &total).is_err());
+
+        set_time(1000 + Window::Hour.seconds());
+        assert!(ledger
+            .check("@a:x", "!r:x", "m", 50, 0.0, &windowed)
+            .is_ok());
+        let over = ledger
+            .check("@a:x", "!r:x", "m", 50, 0.0, &total)
+            .unwrap_err();
+        assert_eq!(over.resets_in, None);
+    }
+
+    #[test]
+    fn model_budgets_only_count_their_model() {
+        let mut ledger = ledger();
+        let limits = [tokens(100, None, Some("a"))];
+        ledger.record("@a:x", "!r:x", "b", &usage(90), 0.0, 0);
+        assert!(ledger.check("@a:x", "!r:x", "a", 50, 0.0, &limits).is_ok());
+        ledger.record("@a:x", "!r:x", "a", &usage(90), 0.0, 0);
+        assert!(ledger.check("@a:x", "!r:x", "a", 50, 0.0, &limits).is_err());
+        assert!(ledger.check("@a:x", "!r:x", "b", 50, 0.0, &limits).is_ok());
+    }
+
+    #[test]
+    fn resets_when_enough_requests_leave_the_window() {
+        let mut ledger = ledger();
+        let limits = [tokens(100, Some(Window::Hour), None)];
+        let keep = Window::Hour.seconds();
+        set_time(10000);
+        ledger.record("@a:x", "!r:x", "m", &usage(40), 0.0, keep);
+        set_time(10600);
+        ledger.record("@a:x", "!r:x", "m", &usage(40), 0.0, keep);
+
+        set_time(11200);
+        let over = ledger
+            .check("@a:x", "!r:x", "m", 30, 0.0, &limits)
+            .unwrap_err();
+        assert_eq!(over.resets_in, Some(2400));
+        // Both requests have to leave the window for this one
+        let over = ledger
+            .check("@a:x", "!r:x", "m", 70, 0.0, &limits)
+            .unwrap_err();
+        assert_eq!(over.resets_in, Some(3000));
+        // Too big for the budget, so it never fits
+        let over = ledger
+            .check("@a:x", "!r:x", "m", 150, 0.0, &limits)
+            .unwrap_err();
+        assert_eq!(over.resets_in, None);
+    }
+
+    #[test]
+    fn keeps_requests_only_as_long_as_needed() {
+        let mut ledger = ledger();
+        set_time(0);
+        ledger.record("@a:x", "!r:x", "m", &usage(10), 0.0, 60);
+        set_time(100);
+        ledger.record("@a:x", "!r:x", "m", &usage(10), 0.0, 60);
+        assert_eq!(ledger.ledger.recent.len(), 1);
+        ledger.record("@a:x", "!r:x", "m", &usage(10), 0.0, 0);
+        assert!(ledger.ledger.recent.is_empty());
+        // The totals are kept forever
+        assert_eq!(ledger.user_totals("@a:x")["m"].requests, 3);
+        assert_eq!(ledger.user_totals("@a:x")["m"].tokens(), 30);
+    }
+}